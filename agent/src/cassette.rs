@@ -0,0 +1,225 @@
+use crate::chat_backend::{BackendError, ChatBackend};
+use async_trait::async_trait;
+use endpoints::chat::{ChatCompletionObject, ChatCompletionRequest};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Request fields that don't change what the model answers, or that depend on the backend
+/// rather than the agent, so a replay matches whichever backend recorded it.
+const IGNORED_REQUEST_FIELDS: &[&str] = &["stream", "stream_options", "user", "response_format"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    /// Everything goes to the real backend and tools, and is written down.
+    Record,
+    /// Everything is answered from the cassette; nothing reaches a model or the network.
+    Replay,
+}
+
+/// One recorded exchange, as stored on a line of the cassette file.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CassetteEntry {
+    /// "chat", "tool" or "python".
+    pub kind: String,
+    pub key: String,
+    pub request: Value,
+    pub response: Value,
+}
+
+/// Model replies, tool results and Python runs keyed by a hash of the normalized request,
+/// so a session recorded once can be replayed without a model or network.
+/// A request made several times is answered with its recorded responses in order, the
+/// last one repeating.
+pub struct Cassette {
+    mode: CassetteMode,
+    entries: Mutex<HashMap<String, VecDeque<Value>>>,
+    file: Option<Mutex<File>>,
+}
+
+impl Cassette {
+    /// Starts a new cassette at `path`, replacing any file already there.
+    pub fn record(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Cassette {
+            mode: CassetteMode::Record,
+            entries: Mutex::new(HashMap::new()),
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    pub fn replay(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut entries: HashMap<String, VecDeque<Value>> = HashMap::new();
+        for (n, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = serde_json::from_str::<CassetteEntry>(line).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("cassette line {}: {}", n + 1, e),
+                )
+            })?;
+            entries
+                .entry(entry.key)
+                .or_default()
+                .push_back(entry.response);
+        }
+        Ok(Cassette {
+            mode: CassetteMode::Replay,
+            entries: Mutex::new(entries),
+            file: None,
+        })
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.mode == CassetteMode::Replay
+    }
+
+    /// The hash `request` of the given kind is filed under.
+    pub fn key(kind: &str, request: &Value) -> String {
+        let mut canonical = format!("{}:", kind);
+        write_canonical(request, &mut canonical);
+        format!("{}-{:016x}", kind, fnv1a(canonical.as_bytes()))
+    }
+
+    /// The next recorded response to `request`, when replaying.
+    pub fn lookup(&self, kind: &str, request: &Value) -> Option<Value> {
+        let mut entries = self.entries.lock().unwrap();
+        let responses = entries.get_mut(&Cassette::key(kind, request))?;
+        match responses.len() {
+            0 => None,
+            1 => responses.front().cloned(),
+            _ => responses.pop_front(),
+        }
+    }
+
+    /// Writes `response` down, when recording.
+    pub fn store(&self, kind: &str, request: Value, response: Value) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let entry = CassetteEntry {
+            kind: kind.to_string(),
+            key: Cassette::key(kind, &request),
+            request,
+            response,
+        };
+        let Ok(line) = serde_json::to_string(&entry) else {
+            return;
+        };
+        if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+            println!("[WARN] could not write to the cassette: {}", e);
+        }
+    }
+
+    pub fn tool_request(tool: &str, arguments: &Value) -> Value {
+        json!({ "tool": tool, "arguments": arguments })
+    }
+
+    pub fn python_request(code: &str) -> Value {
+        json!({ "code": code })
+    }
+}
+
+/// A chat request as the cassette sees it: the messages, model and sampling.
+fn normalized_request(chat_request: &ChatCompletionRequest) -> Value {
+    let mut request = serde_json::to_value(chat_request).unwrap_or_default();
+    if let Some(fields) = request.as_object_mut() {
+        fields.retain(|name, value| {
+            !value.is_null() && !IGNORED_REQUEST_FIELDS.contains(&name.as_str())
+        });
+    }
+    request
+}
+
+/// JSON with object keys sorted and no whitespace, so equal requests hash equally.
+fn write_canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(fields) => {
+            let mut names = fields.keys().collect::<Vec<&String>>();
+            names.sort();
+            out.push('{');
+            for (i, name) in names.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(name.clone()).to_string());
+                out.push(':');
+                write_canonical(&fields[name], out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(item, out);
+            }
+            out.push(']');
+        }
+        other => out.push_str(&other.to_string()),
+    }
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` gives the same hash on every build.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Records what `inner` answers, or answers from the cassette without calling it.
+pub struct CassetteBackend {
+    inner: Arc<dyn ChatBackend>,
+    cassette: Arc<Cassette>,
+}
+
+impl CassetteBackend {
+    pub fn new(inner: Arc<dyn ChatBackend>, cassette: Arc<Cassette>) -> Self {
+        CassetteBackend { inner, cassette }
+    }
+}
+
+#[async_trait(?Send)]
+impl ChatBackend for CassetteBackend {
+    fn supports_json_mode(&self) -> bool {
+        self.inner.supports_json_mode()
+    }
+
+    async fn chat(
+        &self,
+        chat_request: &mut ChatCompletionRequest,
+    ) -> Result<ChatCompletionObject, BackendError> {
+        let request = normalized_request(chat_request);
+        match self.cassette.mode() {
+            CassetteMode::Replay => {
+                let response = self
+                    .cassette
+                    .lookup("chat", &request)
+                    .ok_or_else(|| BackendError::CassetteMiss(Cassette::key("chat", &request)))?;
+                Ok(serde_json::from_value(response)?)
+            }
+            CassetteMode::Record => {
+                let res = self.inner.chat(chat_request).await?;
+                self.cassette
+                    .store("chat", request, serde_json::to_value(&res)?);
+                Ok(res)
+            }
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use async_trait::async_trait;
+use endpoints::{
+    chat::{
+        ChatCompletionObject, ChatCompletionObjectChoice, ChatCompletionObjectMessage,
+        ChatCompletionRequest, ChatCompletionRequestMessage, ChatCompletionRole,
+    },
+    common::{FinishReason, Usage},
+};
+#[cfg(feature = "wasi-nn")]
+use llama_core::LlamaCoreError;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Client;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackendError {
+    #[cfg(feature = "wasi-nn")]
+    #[error("llama-core error: {0}")]
+    LlamaCore(#[from] LlamaCoreError),
+    #[error("http request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("server returned status {status}: {body}")]
+    Status { status: u16, body: String },
+    #[error("failed to decode chat completion: {0}")]
+    Decode(#[from] serde_json::Error),
+    #[error("the chat completion has no choices")]
+    NoChoices,
+    #[error("scripted backend has no reply left for request #{0}")]
+    ScriptExhausted(usize),
+    #[error("the cassette has no recorded reply for request {0}")]
+    CassetteMiss(String),
+    #[error("no chat backend is configured; this build has no local model, so use a server")]
+    NoBackend,
+}
+
+/// A model that can answer a chat completion request.
+#[async_trait(?Send)]
+pub trait ChatBackend: Send + Sync {
+    async fn chat(
+        &self,
+        chat_request: &mut ChatCompletionRequest,
+    ) -> Result<ChatCompletionObject, BackendError>;
+
+    /// Whether the backend honours `response_format: {"type": "json_object"}`, constraining
+    /// the reply to a JSON object.
+    fn supports_json_mode(&self) -> bool {
+        false
+    }
+}
+
+/// The backend agents start with: the local model when the crate is built with it.
+pub fn default_backend() -> Arc<dyn ChatBackend> {
+    #[cfg(feature = "wasi-nn")]
+    return Arc::new(LlamaCoreBackend);
+    #[cfg(not(feature = "wasi-nn"))]
+    return Arc::new(NoBackend);
+}
+
+/// Fails every request; the default when the crate is built without the `wasi-nn` feature.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoBackend;
+
+#[async_trait(?Send)]
+impl ChatBackend for NoBackend {
+    async fn chat(
+        &self,
+        _chat_request: &mut ChatCompletionRequest,
+    ) -> Result<ChatCompletionObject, BackendError> {
+        Err(BackendError::NoBackend)
+    }
+}
+
+/// Runs the request on the wasi-nn model loaded by `llama_core::init_core_context`.
+#[cfg(feature = "wasi-nn")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LlamaCoreBackend;
+
+#[cfg(feature = "wasi-nn")]
+#[async_trait(?Send)]
+impl ChatBackend for LlamaCoreBackend {
+    async fn chat(
+        &self,
+        chat_request: &mut ChatCompletionRequest,
+    ) -> Result<ChatCompletionObject, BackendError> {
+        Ok(llama_core::chat::chat_completions(chat_request).await?)
+    }
+}
+
+/// Talks to any server exposing the OpenAI `/chat/completions` route, e.g. llama.cpp server,
+/// Ollama or LlamaEdge api-server. `base_url` is the part before `/chat/completions`,
+/// such as `http://localhost:8080/v1`.
+pub struct OpenAiHttpBackend {
+    base_url: String,
+    api_key: Option<String>,
+    json_mode: bool,
+    client: Client,
+}
+
+impl OpenAiHttpBackend {
+    pub fn new(base_url: &str) -> Self {
+        OpenAiHttpBackend {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: None,
+            json_mode: true,
+            client: Client::new(),
+        }
+    }
+
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        self.api_key = Some(api_key.to_string());
+        self
+    }
+
+    /// Whether to ask the server for JSON-only replies where the agent expects JSON; on by
+    /// default, off for servers that reject `response_format`.
+    pub fn with_json_mode(mut self, json_mode: bool) -> Self {
+        self.json_mode = json_mode;
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl ChatBackend for OpenAiHttpBackend {
+    fn supports_json_mode(&self) -> bool {
+        self.json_mode
+    }
+
+    async fn chat(
+        &self,
+        chat_request: &mut ChatCompletionRequest,
+    ) -> Result<ChatCompletionObject, BackendError> {
+        // the agent consumes whole replies, never server-sent events
+        chat_request.stream = Some(false);
+        chat_request.stream_options = None;
+
+        let url = format!("{}/chat/completions", self.base_url);
+        let mut request = self
+            .client
+            .post(&url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(chat_request)?);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        let res = request.send().await?;
+        let status = res.status();
+        let body = res.text().await?;
+        if !status.is_success() {
+            return Err(BackendError::Status {
+                status: status.as_u16(),
+                body,
+            });
+        }
+
+        Ok(serde_json::from_str::<ChatCompletionObject>(&body)?)
+    }
+}
+
+/// Replays canned replies in order and keeps every request it received, so agent logic
+/// can be exercised without a model.
+#[derive(Default)]
+pub struct ScriptedBackend {
+    replies: Mutex<VecDeque<String>>,
+    requests: Mutex<Vec<Vec<ChatCompletionRequestMessage>>>,
+}
+
+impl ScriptedBackend {
+    pub fn new<I, S>(replies: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        ScriptedBackend {
+            replies: Mutex::new(replies.into_iter().map(Into::into).collect()),
+            requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn push_reply(&self, reply: &str) {
+        self.replies.lock().unwrap().push_back(reply.to_string());
+    }
+
+    pub fn requests(&self) -> Vec<Vec<ChatCompletionRequestMessage>> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+#[async_trait(?Send)]
+impl ChatBackend for ScriptedBackend {
+    async fn chat(
+        &self,
+        chat_request: &mut ChatCompletionRequest,
+    ) -> Result<ChatCompletionObject, BackendError> {
+        let request_no = {
+            let mut requests = self.requests.lock().unwrap();
+            requests.push(chat_request.messages.clone());
+            requests.len()
+        };
+
+        let content = self
+            .replies
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or(BackendError::ScriptExhausted(request_no))?;
+
+        Ok(ChatCompletionObject {
+            id: format!("scripted-{request_no}"),
+            object: "chat.completion".to_string(),
+            created: 0,
+            model: chat_request.model.clone().unwrap_or_default(),
+            choices: vec![ChatCompletionObjectChoice {
+                index: 0,
+                message: ChatCompletionObjectMessage {
+                    role: ChatCompletionRole::Assistant,
+                    content,
+                    function_call: None,
+                },
+                finish_reason: FinishReason::stop,
+            }],
+            usage: Usage {
+                prompt_tokens: 0,
+                completion_tokens: 0,
+                total_tokens: 0,
+            },
+        })
+    }
+}
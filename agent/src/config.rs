@@ -0,0 +1,636 @@
+use crate::group_chat::*;
+use crate::immutable_agent::ImmutableAgent;
+use crate::templates::*;
+use crate::tools::*;
+use chat_prompts::PromptTemplateType;
+use endpoints::chat::ChatCompletionRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// An agent setup read from a TOML file, so it can be versioned with the project using it.
+/// Every field is optional; whatever is left out keeps its built-in default.
+///
+/// ```toml
+/// prompt_dir = "prompts"
+///
+/// [model]
+/// name = "Hermes-2-Pro-Llama-3-8B"
+/// prompt_template = "chatml"
+/// ctx_size = 8192
+///
+/// [sampling]
+/// temperature = 0.1
+///
+/// [limits]
+/// coding_iterations = 5
+///
+/// [tools.search_with_bing]
+/// count = 3
+///
+/// [prompts]
+/// planning = { file = "prompts/planning.md" }
+///
+/// [[agents]]
+/// name = "user_proxy"
+/// system_prompt = "you're user_proxy"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentConfig {
+    /// Directory of template overrides, one file per template named after it,
+    /// e.g. `coding_fail.txt`.
+    pub prompt_dir: Option<PathBuf>,
+    pub model: ModelConfig,
+    /// Sampling for every agent, unless an agent sets its own.
+    pub sampling: SamplingConfig,
+    pub limits: LimitsConfig,
+    pub tools: ToolsConfig,
+    /// Template overrides for every agent, keyed by the names in `TEMPLATE_VARIABLES`.
+    pub prompts: HashMap<String, PromptSource>,
+    /// The first agent answers in single-agent mode; all of them take part in a group chat.
+    pub agents: Vec<AgentSpec>,
+    pub group_chat: Option<GroupChatConfig>,
+    /// Directory prompt files are resolved against.
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelConfig {
+    pub name: Option<String>,
+    pub alias: Option<String>,
+    /// As accepted by `--prompt-template`, e.g. "chatml" or "llama-3-chat".
+    pub prompt_template: Option<String>,
+    pub ctx_size: Option<u64>,
+    pub n_predict: Option<u64>,
+    pub n_gpu_layers: Option<u64>,
+    pub batch_size: Option<u64>,
+    pub api_base: Option<String>,
+    pub api_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SamplingConfig {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub presence_penalty: Option<f64>,
+    pub frequency_penalty: Option<f64>,
+    /// Only used when the model is loaded locally; it can't be set per request.
+    pub repeat_penalty: Option<f64>,
+}
+
+impl SamplingConfig {
+    /// `self`, with every value `over` sets taking its place.
+    pub fn merged(&self, over: &SamplingConfig) -> SamplingConfig {
+        SamplingConfig {
+            temperature: over.temperature.or(self.temperature),
+            top_p: over.top_p.or(self.top_p),
+            presence_penalty: over.presence_penalty.or(self.presence_penalty),
+            frequency_penalty: over.frequency_penalty.or(self.frequency_penalty),
+            repeat_penalty: over.repeat_penalty.or(self.repeat_penalty),
+        }
+    }
+
+    pub fn apply_to(&self, request: &mut ChatCompletionRequest) {
+        if let Some(temperature) = self.temperature {
+            request.temperature = Some(temperature);
+        }
+        if let Some(top_p) = self.top_p {
+            request.top_p = Some(top_p);
+        }
+        if let Some(presence_penalty) = self.presence_penalty {
+            request.presence_penalty = Some(presence_penalty);
+        }
+        if let Some(frequency_penalty) = self.frequency_penalty {
+            request.frequency_penalty = Some(frequency_penalty);
+        }
+    }
+}
+
+/// Settings given as command-line flags, which win over every config: the one passed with
+/// `--config` and the variants an eval compares.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub sampling: SamplingConfig,
+    pub react_steps: Option<usize>,
+    pub max_replans: Option<usize>,
+    /// The first agent's system prompt.
+    pub system_prompt: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub coding_iterations: Option<usize>,
+    pub react_steps: Option<usize>,
+    /// Times a planner or gatekeeper reply that doesn't match its schema is asked for again.
+    pub structured_retries: Option<usize>,
+    /// Turns re-planning on, allowing this many revisions.
+    pub max_replans: Option<usize>,
+    pub group_chat_rounds: Option<usize>,
+    /// Tokens a single run may spend before it stops with a partial result.
+    pub token_budget: Option<u64>,
+    /// Model calls a single run may make before it stops with a partial result.
+    pub call_budget: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToolsConfig {
+    pub get_webpage_text: ToolSettings,
+    pub search_with_bing: SearchSettings,
+    pub code_with_python: ToolSettings,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToolSettings {
+    pub enabled: bool,
+}
+
+impl Default for ToolSettings {
+    fn default() -> Self {
+        ToolSettings { enabled: true }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchSettings {
+    pub enabled: bool,
+    /// Number of results per search.
+    pub count: usize,
+}
+
+impl Default for SearchSettings {
+    fn default() -> Self {
+        SearchSettings {
+            enabled: true,
+            count: SearchWithBing::default().count,
+        }
+    }
+}
+
+/// A prompt given inline, as `{ file = "..." }` relative to the config file, or as
+/// `{ builtin = "..." }` naming one of the alternative wordings shipped with the crate.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum PromptSource {
+    Inline(String),
+    File {
+        file: PathBuf,
+    },
+    /// One of the `ALTERNATIVE_TEMPLATES` shipped with the crate.
+    Builtin {
+        builtin: String,
+    },
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentSpec {
+    pub name: String,
+    pub system_prompt: Option<String>,
+    /// What the agent is for, shown to the rest of a group chat.
+    pub description: Option<String>,
+    /// Model name sent with this agent's requests, when it differs from `[model]`.
+    pub model: Option<String>,
+    pub sampling: SamplingConfig,
+    /// Names of the enabled tools this agent may use; all of them when unset.
+    pub tools: Option<Vec<String>>,
+    /// Prompt overrides for this agent only, on top of the shared `[prompts]`.
+    pub prompts: HashMap<String, PromptSource>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupChatConfig {
+    /// "round-robin", "llm" or "rules".
+    pub selection: String,
+    /// Used when `selection` is "rules".
+    pub rules: Vec<SpeakerRule>,
+    pub termination_keyword: Option<String>,
+}
+
+impl Default for GroupChatConfig {
+    fn default() -> Self {
+        GroupChatConfig {
+            selection: "round-robin".to_string(),
+            rules: Vec::new(),
+            termination_keyword: None,
+        }
+    }
+}
+
+impl GroupChatConfig {
+    pub fn speaker_selection(&self) -> anyhow::Result<SpeakerSelection> {
+        match self.selection.as_str() {
+            "rules" => Ok(SpeakerSelection::RuleBased(self.rules.clone())),
+            other => other.parse().map_err(anyhow::Error::msg),
+        }
+    }
+}
+
+impl AgentConfig {
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read config {}: {}", path.display(), e))?;
+        let base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        AgentConfig::from_toml_str(&text, base_dir)
+            .map_err(|e| anyhow::anyhow!("Invalid config {}: {}", path.display(), e))
+    }
+
+    pub fn from_toml_str(text: &str, base_dir: PathBuf) -> anyhow::Result<Self> {
+        let mut config: AgentConfig = toml::from_str(text)?;
+        config.base_dir = base_dir;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Catches names that would otherwise be ignored silently.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.prompt_template()?;
+
+        self.templates(None)?;
+        for agent in &self.agents {
+            self.templates(Some(agent))?;
+        }
+
+        let tool_names = self.tool_registry().names().join(", ");
+        for agent in &self.agents {
+            if agent.name.is_empty() {
+                anyhow::bail!("Every agent needs a name");
+            }
+            if self.agents.iter().filter(|a| a.name == agent.name).count() > 1 {
+                anyhow::bail!("Agent name '{}' is used more than once", agent.name);
+            }
+            for tool in agent.tools.iter().flatten() {
+                if !self.tool_registry().names().contains(&tool.as_str()) {
+                    anyhow::bail!(
+                        "Agent '{}' uses tool '{}', which is unknown or disabled; enabled tools: {}",
+                        agent.name,
+                        tool,
+                        tool_names
+                    );
+                }
+            }
+        }
+
+        if let Some(group_chat) = &self.group_chat {
+            group_chat.speaker_selection()?;
+            for rule in &group_chat.rules {
+                if !self.agents.iter().any(|a| a.name == rule.next) {
+                    anyhow::bail!("Speaker rule names unknown agent '{}'", rule.next);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes `overrides` win over whatever the file sets, for every agent in it.
+    pub fn override_with(&mut self, overrides: &Overrides) {
+        self.sampling = self.sampling.merged(&overrides.sampling);
+        for agent in &mut self.agents {
+            agent.sampling = agent.sampling.merged(&overrides.sampling);
+        }
+        if let Some(react_steps) = overrides.react_steps {
+            self.limits.react_steps = Some(react_steps);
+        }
+        if let Some(max_replans) = overrides.max_replans {
+            self.limits.max_replans = Some(max_replans);
+        }
+        if let (Some(system_prompt), Some(agent)) =
+            (&overrides.system_prompt, self.agents.first_mut())
+        {
+            agent.system_prompt = Some(system_prompt.clone());
+        }
+    }
+
+    /// Checks that `variant` can be evaluated next to this config. Variants share the
+    /// model and backend set up for this one, so a variant's `[model]` must be the same.
+    pub fn check_variant(&self, variant: &AgentConfig) -> anyhow::Result<()> {
+        if variant.model != self.model {
+            anyhow::bail!(
+                "A variant's [model] must match the main config's, since every variant runs \
+                 on the same model and backend; compare models with separate eval runs"
+            );
+        }
+        Ok(())
+    }
+
+    pub fn prompt_template(&self) -> anyhow::Result<Option<PromptTemplateType>> {
+        self.model
+            .prompt_template
+            .as_deref()
+            .map(|t| {
+                t.parse::<PromptTemplateType>()
+                    .map_err(|e| anyhow::anyhow!("Invalid prompt_template '{}': {}", t, e))
+            })
+            .transpose()
+    }
+
+    /// The enabled tools, with their settings.
+    pub fn tool_registry(&self) -> ToolRegistry {
+        let mut tools = ToolRegistry::new();
+        if self.tools.get_webpage_text.enabled {
+            tools.register(Arc::new(GetWebpageText));
+        }
+        if self.tools.search_with_bing.enabled {
+            tools.register(Arc::new(SearchWithBing {
+                count: self.tools.search_with_bing.count,
+            }));
+        }
+        if self.tools.code_with_python.enabled {
+            tools.register(Arc::new(CodeWithPython));
+        }
+        tools
+    }
+
+    fn read_prompt(&self, source: &PromptSource) -> anyhow::Result<String> {
+        match source {
+            PromptSource::Inline(text) => Ok(text.clone()),
+            PromptSource::File { file } => {
+                let path = self.base_dir.join(file);
+                std::fs::read_to_string(&path).map_err(|e| {
+                    anyhow::anyhow!("Failed to read prompt file {}: {}", path.display(), e)
+                })
+            }
+            PromptSource::Builtin { builtin } => alternative_source(builtin)
+                .map(str::to_string)
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Unknown builtin prompt '{}', expected one of: {}",
+                        builtin,
+                        ALTERNATIVE_TEMPLATES.join(", ")
+                    )
+                }),
+        }
+    }
+
+    /// The built-in templates with the overrides from `prompt_dir`, `[prompts]` and then
+    /// `spec`'s own prompts applied on top.
+    pub fn templates(&self, spec: Option<&AgentSpec>) -> anyhow::Result<TemplateSet> {
+        let mut templates = TemplateSet::builtin();
+        if let Some(dir) = &self.prompt_dir {
+            templates.load_dir(&self.base_dir.join(dir))?;
+        }
+        let overrides = self
+            .prompts
+            .iter()
+            .chain(spec.into_iter().flat_map(|s| s.prompts.iter()));
+        for (name, source) in overrides {
+            templates.set(name, &self.read_prompt(source)?)?;
+        }
+        Ok(templates)
+    }
+
+    /// `base` with this config's shared tools, limits, sampling and prompt overrides.
+    pub fn apply_to(&self, base: ImmutableAgent) -> anyhow::Result<ImmutableAgent> {
+        let mut agent = base.with_tools(self.tool_registry());
+        if let Some(coding_iterations) = self.limits.coding_iterations {
+            agent = agent.with_coding_iterations(coding_iterations);
+        }
+        if let Some(react_steps) = self.limits.react_steps {
+            agent = agent.with_react_steps(react_steps);
+        }
+        if let Some(structured_retries) = self.limits.structured_retries {
+            agent = agent.with_structured_retries(structured_retries);
+        }
+        if let Some(max_replans) = self.limits.max_replans {
+            agent = agent.with_replanning(max_replans);
+        }
+        self.sampling.apply_to(&mut agent.request_template);
+        Ok(agent.with_templates(self.templates(None)?))
+    }
+
+    /// The agent `spec` describes, built on `base`'s backend and settings.
+    pub fn build_agent(
+        &self,
+        spec: &AgentSpec,
+        base: &ImmutableAgent,
+    ) -> anyhow::Result<ImmutableAgent> {
+        let system_prompt = spec.system_prompt.as_deref().unwrap_or(&base.system_prompt);
+        let mut agent = self.apply_to(base.clone_as(&spec.name, system_prompt))?;
+
+        if let Some(model) = &spec.model {
+            agent.request_template.model = Some(model.clone());
+        }
+        spec.sampling.apply_to(&mut agent.request_template);
+        if let Some(names) = &spec.tools {
+            let mut tools = ToolRegistry::new();
+            for tool in agent
+                .tools
+                .iter()
+                .filter(|t| names.iter().any(|n| n == t.name()))
+            {
+                tools.register(tool.clone());
+            }
+            agent = agent.with_tools(tools);
+        }
+        Ok(agent.with_templates(self.templates(Some(spec))?))
+    }
+
+    /// Every configured agent, or just `base` with the shared settings if none are.
+    pub fn build_agents(&self, base: &ImmutableAgent) -> anyhow::Result<Vec<ImmutableAgent>> {
+        if self.agents.is_empty() {
+            return Ok(vec![
+                self.apply_to(base.clone_as(&base.name, &base.system_prompt))?
+            ]);
+        }
+        self.agents
+            .iter()
+            .map(|spec| self.build_agent(spec, base))
+            .collect()
+    }
+
+    /// A group chat of the configured agents, or of the default team when fewer than two
+    /// are configured. `selection` overrides the configured speaker selection.
+    pub fn build_group_chat(
+        &self,
+        base: &ImmutableAgent,
+        selection: Option<SpeakerSelection>,
+    ) -> anyhow::Result<GroupChat> {
+        let group_config = self.group_chat.clone().unwrap_or_default();
+        let selection = match selection {
+            Some(selection) => selection,
+            None => group_config.speaker_selection()?,
+        };
+
+        let mut group_chat = if self.agents.len() < 2 {
+            GroupChat::default_team(
+                &self.apply_to(base.clone_as(&base.name, &base.system_prompt))?,
+                selection,
+            )
+        } else {
+            let mut group_chat = GroupChat::new(selection);
+            for spec in &self.agents {
+                let description = spec.description.clone().unwrap_or_default();
+                group_chat = group_chat.with_member(self.build_agent(spec, base)?, &description);
+            }
+            group_chat
+        };
+
+        if let Some(rounds) = self.limits.group_chat_rounds {
+            group_chat = group_chat.with_max_rounds(rounds);
+        }
+        if let Some(keyword) = &group_config.termination_keyword {
+            group_chat = group_chat.with_termination_keyword(keyword);
+        }
+        Ok(group_chat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> anyhow::Result<AgentConfig> {
+        AgentConfig::from_toml_str(text, PathBuf::new())
+    }
+
+    const CONFIG: &str = r#"
+[model]
+name = "Hermes-2-Pro-Llama-3-8B"
+prompt_template = "chatml"
+
+[sampling]
+temperature = 0.3
+top_p = 0.9
+
+[limits]
+react_steps = 4
+max_replans = 2
+
+[tools.search_with_bing]
+count = 3
+
+[tools.code_with_python]
+enabled = false
+
+[prompts]
+coding_retry = "Try again: {{error}}"
+
+[[agents]]
+name = "researcher"
+system_prompt = "you find things out"
+tools = ["search_with_bing"]
+
+[agents.sampling]
+temperature = 0.7
+
+[[agents]]
+name = "writer"
+"#;
+
+    #[test]
+    fn parses_toml() {
+        let config = parse(CONFIG).unwrap();
+        assert_eq!(
+            config.model.name.as_deref(),
+            Some("Hermes-2-Pro-Llama-3-8B")
+        );
+        assert_eq!(config.sampling.temperature, Some(0.3));
+        assert_eq!(config.limits.react_steps, Some(4));
+        assert_eq!(config.tools.search_with_bing.count, 3);
+        assert_eq!(
+            config.tool_registry().names(),
+            ["get_webpage_text", "search_with_bing"]
+        );
+        assert_eq!(config.agents[0].sampling.temperature, Some(0.7));
+        assert_eq!(
+            config
+                .templates(None)
+                .unwrap()
+                .render("coding_retry", &[("error", "boom")]),
+            "Try again: boom"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_fields_and_names() {
+        let cases = [
+            ("[model]\nnmae = \"x\"", "unknown field `nmae`"),
+            ("[sampling]\ntemprature = 0.1", "unknown field `temprature`"),
+            ("[[agents]]\nname = \"a\"\nrole = \"b\"", "unknown field `role`"),
+            ("[prompts]\nplaning = \"x\"", "unknown template 'planing'"),
+            ("[prompts]\ncoding_retry = \"{{code}}\"", "uses '{{code}}'"),
+            (
+                "[[agents]]\nname = \"a\"\ntools = [\"code_with_python\"]\n[tools.code_with_python]\nenabled = false",
+                "unknown or disabled",
+            ),
+            (
+                "[[agents]]\nname = \"a\"\n[[agents]]\nname = \"a\"",
+                "used more than once",
+            ),
+            (
+                "[model]\nprompt_template = \"no-such-template\"",
+                "Invalid prompt_template",
+            ),
+        ];
+        for (text, expected) in cases {
+            let error = parse(text).unwrap_err().to_string();
+            assert!(error.contains(expected), "{:?}: {}", text, error);
+        }
+    }
+
+    #[test]
+    fn command_line_wins_over_the_file() {
+        let mut config = parse(CONFIG).unwrap();
+        config.override_with(&Overrides {
+            sampling: SamplingConfig {
+                temperature: Some(0.0),
+                ..Default::default()
+            },
+            react_steps: Some(12),
+            max_replans: None,
+            system_prompt: Some("from the command line".to_string()),
+        });
+
+        let agents = config
+            .build_agents(&ImmutableAgent::new("base", "base prompt"))
+            .unwrap();
+        let researcher = &agents[0];
+        assert_eq!(researcher.system_prompt, "from the command line");
+        assert_eq!(researcher.request_template.temperature, Some(0.0));
+        assert_eq!(researcher.request_template.top_p, Some(0.9));
+        assert_eq!(researcher.max_react_steps, 12);
+        assert_eq!(researcher.max_replans, Some(2));
+        assert_eq!(researcher.tools.names(), ["search_with_bing"]);
+        assert_eq!(agents[1].request_template.temperature, Some(0.0));
+    }
+
+    #[test]
+    fn agent_sampling_wins_over_the_shared_sampling() {
+        let config = parse(CONFIG).unwrap();
+        let agents = config
+            .build_agents(&ImmutableAgent::new("base", "base prompt"))
+            .unwrap();
+        assert_eq!(agents[0].request_template.temperature, Some(0.7));
+        assert_eq!(agents[1].request_template.temperature, Some(0.3));
+        assert_eq!(agents[1].system_prompt, "base prompt");
+    }
+
+    #[test]
+    fn variants_share_the_model() {
+        let config = parse(CONFIG).unwrap();
+        let same_model = parse(
+            "[model]\nname = \"Hermes-2-Pro-Llama-3-8B\"\nprompt_template = \"chatml\"\n\
+             [sampling]\ntemperature = 0.9",
+        )
+        .unwrap();
+        assert!(config.check_variant(&same_model).is_ok());
+
+        let other_server = parse(
+            "[model]\nname = \"Hermes-2-Pro-Llama-3-8B\"\nprompt_template = \"chatml\"\n\
+             api_base = \"http://localhost:9000/v1\"",
+        )
+        .unwrap();
+        assert!(config.check_variant(&other_server).is_err());
+        assert!(config.check_variant(&AgentConfig::default()).is_err());
+    }
+}
@@ -0,0 +1,237 @@
+use endpoints::chat::{
+    ChatCompletionRequest, ChatCompletionRequestMessage, ChatCompletionUserMessageContent,
+    ContentPart,
+};
+use serde::{Deserialize, Serialize};
+
+/// The part an agent is playing for a given model call; each role keeps its own thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentRole {
+    Planner,
+    ToolRouter,
+    Coder,
+    Gatekeeper,
+    /// Thinks and acts in turns in the ReAct loop.
+    Reasoner,
+}
+
+impl AgentRole {
+    pub fn label(&self) -> &'static str {
+        match self {
+            AgentRole::Planner => "planner",
+            AgentRole::ToolRouter => "tool_router",
+            AgentRole::Coder => "coder",
+            AgentRole::Gatekeeper => "gatekeeper",
+            AgentRole::Reasoner => "reasoner",
+        }
+    }
+
+    /// How much of its own history a role sends along with each new request.
+    pub fn default_scope(&self) -> HistoryScope {
+        match self {
+            AgentRole::Planner => HistoryScope::LastTurns(3),
+            AgentRole::ToolRouter => HistoryScope::None,
+            AgentRole::Coder => HistoryScope::All,
+            AgentRole::Gatekeeper => HistoryScope::None,
+            AgentRole::Reasoner => HistoryScope::All,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryScope {
+    None,
+    /// The last N user turns, each with the replies that followed it.
+    LastTurns(usize),
+    All,
+}
+
+/// One thread of a dialogue: a single system prompt plus the user/assistant history.
+/// Every request is built fresh from it, so system prompts never pile up.
+#[derive(Debug, Clone)]
+pub struct Conversation {
+    system_prompt: String,
+    history: Vec<ChatCompletionRequestMessage>,
+    scope: HistoryScope,
+}
+
+impl Conversation {
+    pub fn new(system_prompt: &str) -> Self {
+        Conversation {
+            system_prompt: system_prompt.to_string(),
+            history: Vec::new(),
+            scope: HistoryScope::All,
+        }
+    }
+
+    pub fn for_role(role: AgentRole, system_prompt: &str) -> Self {
+        Conversation::new(system_prompt).with_scope(role.default_scope())
+    }
+
+    pub fn with_scope(mut self, scope: HistoryScope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    pub fn system_prompt(&self) -> &str {
+        &self.system_prompt
+    }
+
+    pub fn set_system_prompt(&mut self, system_prompt: &str) {
+        self.system_prompt = system_prompt.to_string();
+    }
+
+    pub fn history(&self) -> &[ChatCompletionRequestMessage] {
+        &self.history
+    }
+
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+    }
+
+    pub fn push_user(&mut self, text: &str) {
+        self.history
+            .push(ChatCompletionRequestMessage::new_user_message(
+                ChatCompletionUserMessageContent::Text(text.to_string()),
+                None,
+            ));
+    }
+
+    pub fn push_assistant(&mut self, text: &str) {
+        self.history
+            .push(ChatCompletionRequestMessage::new_assistant_message(
+                Some(text.to_string()),
+                None,
+                None,
+            ));
+    }
+
+    pub fn record_turn(&mut self, user_input: &str, reply: &str) {
+        self.push_user(user_input);
+        self.push_assistant(reply);
+    }
+
+    /// Removes and returns everything older than the last `keep_turns` user turns.
+    pub fn drain_older_turns(&mut self, keep_turns: usize) -> Vec<ChatCompletionRequestMessage> {
+        let user_positions = self.user_positions();
+        let split = match user_positions.len().checked_sub(keep_turns) {
+            Some(first) => user_positions
+                .get(first)
+                .copied()
+                .unwrap_or(self.history.len()),
+            None => 0,
+        };
+        self.history.drain(..split).collect()
+    }
+
+    /// Rough size of the request `build_messages(user_input)` would produce.
+    pub fn projected_tokens(&self, user_input: &str) -> u64 {
+        self.build_messages(user_input)
+            .iter()
+            .map(|m| estimate_tokens(&message_text(m)))
+            .sum()
+    }
+
+    fn user_positions(&self) -> Vec<usize> {
+        self.history
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| matches!(m, ChatCompletionRequestMessage::User(_)))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    fn selected_history(&self) -> &[ChatCompletionRequestMessage] {
+        match self.scope {
+            HistoryScope::None => &[],
+            HistoryScope::All => &self.history,
+            HistoryScope::LastTurns(n) => {
+                let user_positions = self.user_positions();
+                if n == 0 {
+                    return &[];
+                }
+                match user_positions.len().checked_sub(n) {
+                    Some(first) => &self.history[user_positions[first]..],
+                    None => &self.history,
+                }
+            }
+        }
+    }
+
+    /// A copy with just the history in scope, sent in full from then on, so that an
+    /// exchange of several requests stays together whatever the thread's scope.
+    pub fn exchange(&self) -> Conversation {
+        Conversation {
+            system_prompt: self.system_prompt.clone(),
+            history: self.selected_history().to_vec(),
+            scope: HistoryScope::All,
+        }
+    }
+
+    /// The message list for the next call: the system prompt, the history in scope, then `user_input`.
+    pub fn build_messages(&self, user_input: &str) -> Vec<ChatCompletionRequestMessage> {
+        let mut messages = vec![ChatCompletionRequestMessage::new_system_message(
+            &self.system_prompt,
+            None,
+        )];
+        messages.extend_from_slice(self.selected_history());
+        messages.push(ChatCompletionRequestMessage::new_user_message(
+            ChatCompletionUserMessageContent::Text(user_input.to_string()),
+            None,
+        ));
+        messages
+    }
+}
+
+pub fn message_text(message: &ChatCompletionRequestMessage) -> String {
+    match message {
+        ChatCompletionRequestMessage::System(m) => m.content().to_string(),
+        ChatCompletionRequestMessage::User(m) => match m.content() {
+            ChatCompletionUserMessageContent::Text(text) => text.clone(),
+            ChatCompletionUserMessageContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text(text) => Some(text.text().to_string()),
+                    _ => None,
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+        },
+        ChatCompletionRequestMessage::Assistant(m) => m.content().cloned().unwrap_or_default(),
+        ChatCompletionRequestMessage::Tool(m) => m.content().to_string(),
+    }
+}
+
+/// Without the model's tokenizer at hand, assume ~4 characters per token plus a few
+/// tokens of chat-template framing per message.
+pub fn estimate_tokens(text: &str) -> u64 {
+    (text.chars().count() as u64).div_ceil(4) + 4
+}
+
+/// A new request carrying `messages` and the sampling settings of `template`.
+pub fn request_from_template(
+    template: &ChatCompletionRequest,
+    messages: Vec<ChatCompletionRequestMessage>,
+) -> ChatCompletionRequest {
+    ChatCompletionRequest {
+        model: template.model.clone(),
+        messages,
+        temperature: template.temperature,
+        top_p: template.top_p,
+        n_choice: template.n_choice,
+        stream: template.stream,
+        stream_options: template.stream_options.clone(),
+        stop: template.stop.clone(),
+        max_tokens: template.max_tokens,
+        presence_penalty: template.presence_penalty,
+        frequency_penalty: template.frequency_penalty,
+        logit_bias: template.logit_bias.clone(),
+        user: template.user.clone(),
+        functions: None,
+        function_call: None,
+        response_format: template.response_format.clone(),
+        tools: template.tools.clone(),
+        tool_choice: template.tool_choice.clone(),
+    }
+}
@@ -0,0 +1,40 @@
+use crate::chat_backend::BackendError;
+
+/// Everything that can go wrong while the agent works on a task. None of these end the
+/// process; the caller decides whether to retry, report or move on.
+#[derive(Debug, thiserror::Error)]
+pub enum AgentError {
+    #[error(transparent)]
+    Backend(#[from] BackendError),
+    /// The model replied, but not in the shape the prompt asked for.
+    #[error("could not parse model reply: {0}")]
+    Parse(String),
+    #[error("tool '{tool}' failed: {message}")]
+    Tool { tool: String, message: String },
+    #[error("python execution failed: {0}")]
+    Execution(String),
+    #[error("{what} limit of {limit} reached")]
+    LimitExceeded { what: String, limit: usize },
+    #[error("stopped by the user")]
+    Aborted,
+    /// The run spent its token or call budget; what it has done so far is kept.
+    #[error("{what} budget of {limit} used up")]
+    BudgetExhausted { what: String, limit: u64 },
+    /// A saved run could not be loaded or rewound.
+    #[error("cannot resume run: {0}")]
+    Resume(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl AgentError {
+    /// Errors that end the whole run rather than just the step or tool call they came from.
+    pub fn ends_run(&self) -> bool {
+        matches!(
+            self,
+            AgentError::Aborted | AgentError::BudgetExhausted { .. }
+        )
+    }
+}
+
+pub type AgentResult<T> = Result<T, AgentError>;
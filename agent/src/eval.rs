@@ -0,0 +1,359 @@
+use crate::error::*;
+use crate::immutable_agent::*;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Instant;
+
+/// How an answer is checked against `EvalTask::expected`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Scoring {
+    /// Equal after trimming, ignoring case and runs of whitespace.
+    #[default]
+    Exact,
+    /// `expected` is a regular expression the answer must match.
+    Regex,
+    /// Some number in the answer is within `tolerance` of `expected`.
+    Numeric {
+        #[serde(default)]
+        tolerance: f64,
+    },
+    /// A model call compares the answer with `expected` as a reference answer.
+    Judge,
+}
+
+/// One line of a suite file, e.g.
+/// `{"id": "sqrt", "task": "What is the square root of 1764?", "expected": "42", "scoring": {"type": "numeric", "tolerance": 0.01}}`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EvalTask {
+    /// Defaults to the line number.
+    #[serde(default)]
+    pub id: String,
+    pub task: String,
+    pub expected: String,
+    #[serde(default)]
+    pub scoring: Scoring,
+}
+
+/// Reads a JSONL suite, skipping blank lines.
+pub fn load_suite(path: impl AsRef<Path>) -> anyhow::Result<Vec<EvalTask>> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read suite {}: {}", path.display(), e))?;
+    let mut tasks = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut task = serde_json::from_str::<EvalTask>(line)
+            .map_err(|e| anyhow::anyhow!("{} line {}: {}", path.display(), n + 1, e))?;
+        if let Scoring::Regex = task.scoring {
+            Regex::new(&task.expected).map_err(|e| {
+                anyhow::anyhow!("{} line {}: invalid regex: {}", path.display(), n + 1, e)
+            })?;
+        }
+        if task.id.is_empty() {
+            task.id = (n + 1).to_string();
+        }
+        tasks.push(task);
+    }
+    Ok(tasks)
+}
+
+/// How the agent did on one task.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TaskResult {
+    pub id: String,
+    pub passed: bool,
+    pub answer: String,
+    pub error: Option<String>,
+    /// Plan steps finished, or turns taken in react mode.
+    pub steps: usize,
+    pub tool_calls: usize,
+    pub model_calls: usize,
+    pub tokens: u64,
+    pub latency_ms: u64,
+}
+
+/// One configuration's results on a suite.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EvalReport {
+    pub variant: String,
+    pub results: Vec<TaskResult>,
+}
+
+impl EvalReport {
+    pub fn accuracy(&self) -> f64 {
+        if self.results.is_empty() {
+            return 0.0;
+        }
+        self.passed() as f64 / self.results.len() as f64
+    }
+
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|r| r.passed).count()
+    }
+
+    fn mean(&self, value: impl Fn(&TaskResult) -> f64) -> f64 {
+        if self.results.is_empty() {
+            return 0.0;
+        }
+        self.results.iter().map(value).sum::<f64>() / self.results.len() as f64
+    }
+
+    /// A row per task, then the totals.
+    pub fn render(&self) -> String {
+        let mut lines = vec![
+            format!("== {} ==", self.variant),
+            format!(
+                "{:<16} {:<6} {:>5} {:>5} {:>6} {:>8} {:>9}",
+                "task", "result", "steps", "tools", "calls", "tokens", "latency"
+            ),
+        ];
+        for r in &self.results {
+            lines.push(format!(
+                "{:<16} {:<6} {:>5} {:>5} {:>6} {:>8} {:>7}ms",
+                r.id,
+                if r.passed { "pass" } else { "FAIL" },
+                r.steps,
+                r.tool_calls,
+                r.model_calls,
+                r.tokens,
+                r.latency_ms
+            ));
+        }
+        lines.push(format!(
+            "accuracy {}/{} ({:.1}%), mean steps {:.1}, tool calls {:.1}, tokens {:.0}, latency {:.0}ms",
+            self.passed(),
+            self.results.len(),
+            self.accuracy() * 100.0,
+            self.mean(|r| r.steps as f64),
+            self.mean(|r| r.tool_calls as f64),
+            self.mean(|r| r.tokens as f64),
+            self.mean(|r| r.latency_ms as f64),
+        ));
+        lines.join("\n")
+    }
+}
+
+/// A label and how to compute its cell for one report.
+type SummaryRow = (&'static str, fn(&EvalReport) -> String);
+
+/// The reports side by side: a pass/fail column per variant for every task, then each
+/// variant's accuracy and mean cost.
+pub fn compare(reports: &[EvalReport]) -> String {
+    let Some(first) = reports.first() else {
+        return String::new();
+    };
+    let header = reports
+        .iter()
+        .map(|r| format!("{:>14}", truncate(&r.variant, 14)))
+        .collect::<String>();
+    let mut lines = vec![format!("{:<16}{}", "task", header)];
+    for (i, task) in first.results.iter().enumerate() {
+        let cells = reports
+            .iter()
+            .map(|report| match report.results.get(i) {
+                Some(r) if r.passed => format!("{:>14}", "pass"),
+                Some(_) => format!("{:>14}", "FAIL"),
+                None => format!("{:>14}", "-"),
+            })
+            .collect::<String>();
+        lines.push(format!("{:<16}{}", task.id, cells));
+    }
+    let summary_rows: [SummaryRow; 4] = [
+        ("accuracy", |r| format!("{:.1}%", r.accuracy() * 100.0)),
+        ("mean steps", |r| {
+            format!("{:.1}", r.mean(|t| t.steps as f64))
+        }),
+        ("mean tokens", |r| {
+            format!("{:.0}", r.mean(|t| t.tokens as f64))
+        }),
+        ("mean latency", |r| {
+            format!("{:.0}ms", r.mean(|t| t.latency_ms as f64))
+        }),
+    ];
+    for (label, value) in summary_rows {
+        let cells = reports
+            .iter()
+            .map(|r| format!("{:>14}", value(r)))
+            .collect::<String>();
+        lines.push(format!("{:<16}{}", label, cells));
+    }
+    lines.join("\n")
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    text.chars().take(max).collect()
+}
+
+/// Runs every task on `agent` in `mode`, each from fresh threads, and scores the answers.
+pub async fn run_suite(
+    agent: &ImmutableAgent,
+    variant: &str,
+    tasks: &[EvalTask],
+    mode: RunMode,
+) -> EvalReport {
+    let mut results = Vec::new();
+    for task in tasks {
+        println!("[{}] {}: {}", variant, task.id, task.task);
+        agent.reset_threads();
+        let start = Instant::now();
+        let outcome = agent.run(&task.task, mode).await;
+        let latency_ms = start.elapsed().as_millis() as u64;
+
+        let usage = agent.usage.report();
+        let steps = match mode {
+            RunMode::PlanAndExecute => agent
+                .current_run()
+                .map(|run| run.completed.len())
+                .unwrap_or_default(),
+            RunMode::React => usage.by_role.get("reasoner").map_or(0, |u| u.calls),
+        };
+        let mut result = TaskResult {
+            id: task.id.clone(),
+            steps,
+            tool_calls: usage.tool_calls,
+            model_calls: usage.total.calls,
+            tokens: usage.total.total_tokens,
+            latency_ms,
+            ..TaskResult::default()
+        };
+        match outcome {
+            Ok(answer) => {
+                // the judge's calls don't count against the task's budget
+                agent.usage.reset();
+                match score(agent, task, &answer).await {
+                    Ok(passed) => result.passed = passed,
+                    Err(e) => result.error = Some(format!("scoring failed: {}", e)),
+                }
+                result.answer = answer;
+            }
+            Err(e) => result.error = Some(e.to_string()),
+        }
+        println!(
+            "[{}] {}: {}",
+            variant,
+            task.id,
+            if result.passed { "pass" } else { "FAIL" }
+        );
+        results.push(result);
+    }
+    EvalReport {
+        variant: variant.to_string(),
+        results,
+    }
+}
+
+pub async fn score(agent: &ImmutableAgent, task: &EvalTask, answer: &str) -> AgentResult<bool> {
+    match &task.scoring {
+        Scoring::Exact => Ok(normalize(answer) == normalize(&task.expected)),
+        Scoring::Regex => Regex::new(&task.expected)
+            .map(|re| re.is_match(answer))
+            .map_err(|e| AgentError::Parse(e.to_string())),
+        Scoring::Numeric { tolerance } => {
+            let expected = task
+                .expected
+                .trim()
+                .parse::<f64>()
+                .map_err(|e| AgentError::Parse(format!("expected is not a number: {}", e)))?;
+            Ok(numbers_in(answer)
+                .into_iter()
+                .any(|n| (n - expected).abs() <= *tolerance))
+        }
+        Scoring::Judge => {
+            let system_prompt = agent.templates.render(
+                "eval_judge",
+                &[("task", &task.task), ("expected", &task.expected)],
+            );
+            let verdict = agent
+                .complete_full("judge", &system_prompt, answer)
+                .await?
+                .content_to_string();
+            Ok(verdict.trim_start().to_uppercase().starts_with("CORRECT"))
+        }
+    }
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// A number, with commas only as thousands separators so a list like "1,2" is two numbers.
+static NUMBER: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"-?(?:\d{1,3}(?:,\d{3})+|\d+)(?:\.\d+)?(?:[eE]-?\d+)?").unwrap());
+
+/// Every number written in `text`, allowing thousands separators.
+fn numbers_in(text: &str) -> Vec<f64> {
+    NUMBER
+        .find_iter(text)
+        .filter_map(|m| m.as_str().replace(',', "").parse::<f64>().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(expected: &str, scoring: Scoring) -> EvalTask {
+        EvalTask {
+            id: "t".to_string(),
+            task: "a question".to_string(),
+            expected: expected.to_string(),
+            scoring,
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn scoring() {
+        let agent = ImmutableAgent::new("test", "");
+        let numeric = Scoring::Numeric { tolerance: 0.01 };
+        let cases = [
+            ("Paris", Scoring::Exact, "  paris\n", true),
+            ("New York", Scoring::Exact, "new   york", true),
+            ("Paris", Scoring::Exact, "It is Paris", false),
+            (r"\bParis\b", Scoring::Regex, "It is Paris.", true),
+            (r"^Paris$", Scoring::Regex, "It is Paris.", false),
+            ("42", numeric.clone(), "The root is 42.", true),
+            ("42", numeric.clone(), "The root is 42.004", true),
+            ("42", numeric.clone(), "The root is 42.5", false),
+            ("-3", numeric.clone(), "x = -3", true),
+            ("1234567", numeric.clone(), "about 1,234,567 people", true),
+            ("12", numeric.clone(), "the pair 1,2", false),
+            ("2", numeric.clone(), "the pair 1,2", true),
+            ("1500", numeric.clone(), "1.5e3", true),
+        ];
+        for (expected, scoring, answer, passed) in cases {
+            let task = task(expected, scoring);
+            assert_eq!(
+                score(&agent, &task, answer).await.unwrap(),
+                passed,
+                "{:?} against {:?}",
+                answer,
+                task
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn a_numeric_task_needs_a_numeric_answer_key() {
+        let agent = ImmutableAgent::new("test", "");
+        let task = task("forty-two", Scoring::Numeric { tolerance: 0.0 });
+        assert!(score(&agent, &task, "42").await.is_err());
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(
+            numbers_in("1,000 and 2,5 and 3.25"),
+            [1000.0, 2.0, 5.0, 3.25]
+        );
+        assert_eq!(numbers_in("no numbers"), Vec::<f64>::new());
+    }
+}
@@ -0,0 +1,164 @@
+use crate::error::*;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+/// A point in a run where the agent stops to ask for a review.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Checkpoint {
+    /// The generated Python code, before it runs.
+    BeforePython,
+    /// The URL `get_webpage_text` is about to fetch.
+    BeforeFetch,
+    /// The plan, before any step of it runs.
+    AfterPlan,
+    /// The answer, before it is returned.
+    BeforeAnswer,
+}
+
+impl Checkpoint {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Checkpoint::BeforePython => "python code to run",
+            Checkpoint::BeforeFetch => "URL to fetch",
+            Checkpoint::AfterPlan => "plan",
+            Checkpoint::BeforeAnswer => "final answer",
+        }
+    }
+}
+
+impl std::str::FromStr for Checkpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "python" => Ok(Checkpoint::BeforePython),
+            "fetch" => Ok(Checkpoint::BeforeFetch),
+            "plan" => Ok(Checkpoint::AfterPlan),
+            "answer" => Ok(Checkpoint::BeforeAnswer),
+            _ => Err(format!(
+                "unknown checkpoint '{}', expected 'python', 'fetch', 'plan' or 'answer'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Feedback {
+    Approve,
+    /// Go on with this instead of what was shown.
+    Edit(String),
+    /// Don't go on with it; the comment says why.
+    Reject(String),
+    /// Stop the current task. The agent returns `AgentError::Aborted`.
+    Abort,
+}
+
+/// Someone, or something, that reviews the agent's work at a checkpoint.
+#[async_trait(?Send)]
+pub trait FeedbackProvider {
+    async fn review(&self, checkpoint: Checkpoint, content: &str) -> AgentResult<Feedback>;
+}
+
+/// Approves everything without asking.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AutoApprove;
+
+#[async_trait(?Send)]
+impl FeedbackProvider for AutoApprove {
+    async fn review(&self, _checkpoint: Checkpoint, _content: &str) -> AgentResult<Feedback> {
+        Ok(Feedback::Approve)
+    }
+}
+
+/// Asks on stdin/stdout. A closed stdin aborts at the prompt and ends an edit.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TerminalFeedback;
+
+/// A line from stdin, or `None` once it is closed.
+fn read_line() -> AgentResult<Option<String>> {
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(input.trim_end_matches(['\n', '\r']).to_string()))
+}
+
+#[async_trait(?Send)]
+impl FeedbackProvider for TerminalFeedback {
+    async fn review(&self, checkpoint: Checkpoint, content: &str) -> AgentResult<Feedback> {
+        println!("\n[Review the {}]:\n{}\n", checkpoint.label(), content);
+        loop {
+            print!("[Enter] approve, [e] edit, [r <comment>] reject, [q] abort: ");
+            io::stdout().flush()?;
+
+            // with no one left to ask, nothing gets approved by default
+            let Some(input) = read_line()? else {
+                println!("\nstdin is closed; aborting");
+                return Ok(Feedback::Abort);
+            };
+            match input.trim() {
+                "" | "a" => return Ok(Feedback::Approve),
+                "q" => return Ok(Feedback::Abort),
+                "e" => {
+                    println!("Enter the replacement, ending with a line containing only '.':");
+                    let mut lines = Vec::new();
+                    while let Some(line) = read_line()? {
+                        if line == "." {
+                            break;
+                        }
+                        lines.push(line);
+                    }
+                    return Ok(Feedback::Edit(lines.join("\n")));
+                }
+                other => match other.strip_prefix('r') {
+                    Some(comment) if comment.is_empty() || comment.starts_with(' ') => {
+                        return Ok(Feedback::Reject(comment.trim().to_string()))
+                    }
+                    _ => continue,
+                },
+            }
+        }
+    }
+}
+
+/// Replies with a fixed list of feedback in order, then approves; records what it was shown.
+#[derive(Debug, Default)]
+pub struct ScriptedFeedback {
+    replies: Mutex<VecDeque<Feedback>>,
+    reviewed: Mutex<Vec<(Checkpoint, String)>>,
+}
+
+impl ScriptedFeedback {
+    pub fn new(replies: Vec<Feedback>) -> Self {
+        ScriptedFeedback {
+            replies: Mutex::new(replies.into()),
+            reviewed: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Every checkpoint reached so far, with the content shown at it.
+    pub fn reviewed(&self) -> Vec<(Checkpoint, String)> {
+        self.reviewed.lock().unwrap().clone()
+    }
+}
+
+#[async_trait(?Send)]
+impl FeedbackProvider for ScriptedFeedback {
+    async fn review(&self, checkpoint: Checkpoint, content: &str) -> AgentResult<Feedback> {
+        self.reviewed
+            .lock()
+            .unwrap()
+            .push((checkpoint, content.to_string()));
+        Ok(self
+            .replies
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or(Feedback::Approve))
+    }
+}
@@ -0,0 +1,474 @@
+use crate::conversation::estimate_tokens;
+use crate::error::*;
+use crate::immutable_agent::ImmutableAgent;
+use crate::nous_structs::*;
+use crate::tool_prompt::*;
+use crate::tools::*;
+use crate::{
+    CODER_AGENT_PROMPT, CRITIC_AGENT_PROMPT, PLANNER_AGENT_PROMPT, RESEARCHER_AGENT_PROMPT,
+};
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// One message of the shared transcript.
+#[derive(Debug, Clone)]
+pub struct ChatEntry {
+    pub speaker: String,
+    pub content: String,
+}
+
+/// Picks `next` when the last message matches; unset conditions match anything.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SpeakerRule {
+    /// Name of whoever spoke last.
+    #[serde(default)]
+    pub after: Option<String>,
+    /// Text the last message contains, compared case-insensitively.
+    #[serde(default)]
+    pub mentions: Option<String>,
+    pub next: String,
+}
+
+impl SpeakerRule {
+    pub fn after(speaker: &str, next: &str) -> Self {
+        SpeakerRule {
+            after: Some(speaker.to_string()),
+            mentions: None,
+            next: next.to_string(),
+        }
+    }
+
+    pub fn mentions(text: &str, next: &str) -> Self {
+        SpeakerRule {
+            after: None,
+            mentions: Some(text.to_string()),
+            next: next.to_string(),
+        }
+    }
+
+    fn matches(&self, last: &ChatEntry) -> bool {
+        self.after.as_ref().is_none_or(|s| *s == last.speaker)
+            && self
+                .mentions
+                .as_ref()
+                .is_none_or(|m| last.content.to_lowercase().contains(&m.to_lowercase()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum SpeakerSelection {
+    RoundRobin,
+    /// The first matching rule decides; round-robin when none matches.
+    RuleBased(Vec<SpeakerRule>),
+    /// The model picks from the members' names and descriptions; round-robin if its reply
+    /// names nobody.
+    LlmChosen,
+}
+
+impl std::str::FromStr for SpeakerSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(SpeakerSelection::RoundRobin),
+            "llm" => Ok(SpeakerSelection::LlmChosen),
+            _ => Err(format!(
+                "unknown speaker selection '{}', expected 'round-robin' or 'llm'",
+                s
+            )),
+        }
+    }
+}
+
+pub struct GroupMember {
+    pub agent: Rc<ImmutableAgent>,
+    /// What the member is for, shown to the others and to the speaker selector.
+    pub description: String,
+}
+
+pub struct GroupChatOutcome {
+    pub transcript: Vec<ChatEntry>,
+    /// Whether a member ended the chat, rather than it running out of rounds.
+    pub terminated: bool,
+}
+
+impl GroupChatOutcome {
+    /// The last member message, without the termination keyword.
+    pub fn answer(&self, termination_keyword: &str) -> String {
+        self.transcript
+            .iter()
+            .rev()
+            .find(|e| e.speaker != "user")
+            .map(|e| {
+                e.content
+                    .replace(termination_keyword, "")
+                    .trim()
+                    .to_string()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Named agents taking turns on one shared transcript. Each member speaks under its own
+/// system prompt and may call its own tools.
+pub struct GroupChat {
+    pub members: Vec<GroupMember>,
+    pub selection: SpeakerSelection,
+    pub max_rounds: usize,
+    pub termination_keyword: String,
+    /// Agent whose backend chooses speakers for `LlmChosen`; the first member if unset.
+    pub selector: Option<Rc<ImmutableAgent>>,
+}
+
+impl GroupChat {
+    pub fn new(selection: SpeakerSelection) -> Self {
+        GroupChat {
+            members: Vec::new(),
+            selection,
+            max_rounds: 12,
+            termination_keyword: "TERMINATE".to_string(),
+            selector: None,
+        }
+    }
+
+    /// Planner, researcher, coder and critic, all cloned from `base`'s backend and settings.
+    pub fn default_team(base: &ImmutableAgent, selection: SpeakerSelection) -> Self {
+        let no_tools = ToolRegistry::new();
+        let research_tools = ToolRegistry::new()
+            .with_tool(Arc::new(SearchWithBing::default()))
+            .with_tool(Arc::new(GetWebpageText));
+        let coding_tools = ToolRegistry::new().with_tool(Arc::new(CodeWithPython));
+
+        GroupChat::new(selection)
+            .with_member(
+                base.clone_as("planner", PLANNER_AGENT_PROMPT)
+                    .with_tools(no_tools.clone()),
+                "breaks the task into steps and assigns them",
+            )
+            .with_member(
+                base.clone_as("researcher", RESEARCHER_AGENT_PROMPT)
+                    .with_tools(research_tools),
+                "searches the web for facts that need grounding",
+            )
+            .with_member(
+                base.clone_as("coder", CODER_AGENT_PROMPT)
+                    .with_tools(coding_tools),
+                "writes and runs Python for computations",
+            )
+            .with_member(
+                base.clone_as("critic", CRITIC_AGENT_PROMPT)
+                    .with_tools(no_tools),
+                "checks results and gives the final answer",
+            )
+    }
+
+    pub fn with_member(mut self, agent: ImmutableAgent, description: &str) -> Self {
+        self.members.push(GroupMember {
+            agent: Rc::new(agent),
+            description: description.to_string(),
+        });
+        self
+    }
+
+    pub fn with_max_rounds(mut self, max_rounds: usize) -> Self {
+        self.max_rounds = max_rounds;
+        self
+    }
+
+    pub fn with_termination_keyword(mut self, termination_keyword: &str) -> Self {
+        self.termination_keyword = termination_keyword.to_string();
+        self
+    }
+
+    pub fn with_selector(mut self, selector: Rc<ImmutableAgent>) -> Self {
+        self.selector = Some(selector);
+        self
+    }
+
+    fn roster(&self) -> String {
+        self.members
+            .iter()
+            .map(|m| format!("- {}: {}", m.agent.name, m.description))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.members.iter().position(|m| m.agent.name == name)
+    }
+
+    /// Lets the members take turns on `task` until one of them says the termination
+    /// keyword, `max_rounds` turns have been taken or the members' budget runs out.
+    pub async fn run(&self, task: &str) -> AgentResult<GroupChatOutcome> {
+        if self.members.is_empty() {
+            return Err(AgentError::Parse(
+                "the group chat has no members".to_string(),
+            ));
+        }
+
+        let mut transcript = vec![ChatEntry {
+            speaker: "user".to_string(),
+            content: task.to_string(),
+        }];
+        let mut last_speaker: Option<usize> = None;
+
+        for _ in 0..self.max_rounds {
+            let turn = async {
+                let next = self.select_speaker(&transcript, last_speaker).await?;
+                let content = self.take_turn(&self.members[next], &transcript).await?;
+                Ok::<_, AgentError>((next, content))
+            }
+            .await;
+            let (next, content) = match turn {
+                Ok(turn) => turn,
+                Err(e @ AgentError::BudgetExhausted { .. }) => {
+                    println!("Stopping the group chat: {}", e);
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            let member = &self.members[next];
+            println!("[{}]: {}\n", member.agent.name, content);
+
+            let terminated = content.contains(&self.termination_keyword);
+            transcript.push(ChatEntry {
+                speaker: member.agent.name.clone(),
+                content,
+            });
+            if terminated {
+                return Ok(GroupChatOutcome {
+                    transcript,
+                    terminated: true,
+                });
+            }
+            last_speaker = Some(next);
+        }
+
+        Ok(GroupChatOutcome {
+            transcript,
+            terminated: false,
+        })
+    }
+
+    async fn select_speaker(
+        &self,
+        transcript: &[ChatEntry],
+        last_speaker: Option<usize>,
+    ) -> AgentResult<usize> {
+        let round_robin = last_speaker.map_or(0, |i| (i + 1) % self.members.len());
+        let last = transcript
+            .last()
+            .expect("the transcript starts with the task");
+
+        match &self.selection {
+            SpeakerSelection::RoundRobin => Ok(round_robin),
+            SpeakerSelection::RuleBased(rules) => Ok(rules
+                .iter()
+                .find(|rule| rule.matches(last))
+                .and_then(|rule| self.position(&rule.next))
+                .unwrap_or(round_robin)),
+            SpeakerSelection::LlmChosen => {
+                let selector = self.selector.as_ref().unwrap_or(&self.members[0].agent);
+                let system_prompt = selector
+                    .templates
+                    .render("select_speaker", &[("roster", &self.roster())]);
+                let reply = selector
+                    .complete_full(
+                        "speaker_selector",
+                        &system_prompt,
+                        &render_transcript(transcript, transcript_budget(selector, &system_prompt)),
+                    )
+                    .await?
+                    .content_to_string();
+
+                let reply = reply.trim().trim_matches(|c: char| !c.is_alphanumeric());
+                Ok(self
+                    .position(reply)
+                    .or_else(|| {
+                        self.members
+                            .iter()
+                            .position(|m| reply.contains(m.agent.name.as_str()))
+                    })
+                    .unwrap_or(round_robin))
+            }
+        }
+    }
+
+    /// One message from `member`: its own system prompt plus the group rules, the shared
+    /// transcript as input, and any tool calls it makes run with its own tools.
+    async fn take_turn(
+        &self,
+        member: &GroupMember,
+        transcript: &[ChatEntry],
+    ) -> AgentResult<String> {
+        let agent = &member.agent;
+        let mut system_prompt = format!(
+            "{}\n\n{}",
+            agent.system_prompt,
+            agent.templates.render(
+                "group_member",
+                &[
+                    ("name", &agent.name),
+                    ("roster", &self.roster()),
+                    ("termination_keyword", &self.termination_keyword),
+                ],
+            )
+        );
+        let specs = agent.tools.specs();
+        if !specs.is_empty() {
+            system_prompt.push_str(&format!(
+                "\n{}\n\n{}\n\n{}",
+                render_tool_signatures(&specs, agent.tool_syntax),
+                render_tool_examples(&specs),
+                render_tool_call_format()
+            ));
+        }
+
+        let user_prompt = format!(
+            "{}\n\nIt is your turn, {}.",
+            render_transcript(transcript, transcript_budget(agent, &system_prompt)),
+            agent.name
+        );
+        let reply = agent
+            .complete_full(&agent.name, &system_prompt, &user_prompt)
+            .await?;
+
+        match &reply.content {
+            NousContent::Text(t) => Ok(t.trim().to_string()),
+            NousContent::NousToolCalls(calls) => {
+                let result = match agent.run_tool_calls(calls).await {
+                    Ok(result) => result,
+                    Err(e) if e.ends_run() => return Err(e),
+                    Err(e) => format!("Error: {}", e),
+                };
+                Ok(format!(
+                    "{}\n\nResult:\n{}",
+                    reply.content_to_string(),
+                    result
+                ))
+            }
+        }
+    }
+}
+
+/// Tokens left for the transcript once the system prompt and the reply are accounted for.
+fn transcript_budget(agent: &ImmutableAgent, system_prompt: &str) -> u64 {
+    agent
+        .ctx_size
+        .saturating_sub(agent.n_predict)
+        .saturating_sub(estimate_tokens(system_prompt))
+        * 9
+        / 10
+}
+
+/// The transcript as "[speaker]: message" blocks, keeping the task and as many of the most
+/// recent messages as fit in `budget` tokens.
+fn render_transcript(transcript: &[ChatEntry], budget: u64) -> String {
+    let render = |e: &ChatEntry| format!("[{}]: {}", e.speaker, e.content);
+    let (task, rest) = match transcript.split_first() {
+        Some(split) => split,
+        None => return String::new(),
+    };
+
+    let mut used = estimate_tokens(&render(task));
+    let mut recent = Vec::new();
+    for entry in rest.iter().rev() {
+        let text = render(entry);
+        used += estimate_tokens(&text);
+        if used > budget {
+            break;
+        }
+        recent.push(text);
+    }
+    recent.push(render(task));
+    recent.reverse();
+    recent.join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_backend::ScriptedBackend;
+    use crate::conversation::message_text;
+
+    fn chat(selection: SpeakerSelection) -> GroupChat {
+        ["planner", "coder", "critic"]
+            .into_iter()
+            .fold(GroupChat::new(selection), |chat, name| {
+                chat.with_member(ImmutableAgent::new(name, ""), &format!("the {}", name))
+            })
+    }
+
+    fn transcript(last_speaker: &str, content: &str) -> Vec<ChatEntry> {
+        vec![
+            ChatEntry {
+                speaker: "user".to_string(),
+                content: "the task".to_string(),
+            },
+            ChatEntry {
+                speaker: last_speaker.to_string(),
+                content: content.to_string(),
+            },
+        ]
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn round_robin_goes_around() {
+        let chat = chat(SpeakerSelection::RoundRobin);
+        let transcript = transcript("critic", "");
+        for (last, next) in [(None, 0), (Some(0), 1), (Some(1), 2), (Some(2), 0)] {
+            assert_eq!(chat.select_speaker(&transcript, last).await.unwrap(), next);
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn the_first_matching_rule_decides() {
+        let chat = chat(SpeakerSelection::RuleBased(vec![
+            SpeakerRule::mentions("BUG", "coder"),
+            SpeakerRule::after("coder", "critic"),
+            SpeakerRule::after("planner", "nobody"),
+        ]));
+        let cases = [
+            ("critic", "There is a bug in line 3", Some(2), 1),
+            ("coder", "Fixed the bug", Some(1), 1),
+            ("coder", "Done", Some(1), 2),
+            // a rule naming a non-member falls back to round-robin
+            ("planner", "Here is the plan", Some(0), 1),
+            ("critic", "Looks good", Some(2), 0),
+        ];
+        for (speaker, content, last, next) in cases {
+            assert_eq!(
+                chat.select_speaker(&transcript(speaker, content), last)
+                    .await
+                    .unwrap(),
+                next,
+                "after {}: {}",
+                speaker,
+                content
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn the_model_names_the_next_speaker() {
+        let backend = Arc::new(ScriptedBackend::new([
+            "critic",
+            "Next: coder.",
+            "the reviewer",
+        ]));
+        let chat = chat(SpeakerSelection::LlmChosen).with_selector(Rc::new(
+            ImmutableAgent::new("selector", "").with_backend(backend.clone()),
+        ));
+        let transcript = transcript("planner", "Here is the plan");
+
+        assert_eq!(chat.select_speaker(&transcript, Some(0)).await.unwrap(), 2);
+        assert_eq!(chat.select_speaker(&transcript, Some(0)).await.unwrap(), 1);
+        // a reply naming no member falls back to round-robin
+        assert_eq!(chat.select_speaker(&transcript, Some(2)).await.unwrap(), 0);
+
+        let request = &backend.requests()[0];
+        assert!(message_text(&request[0]).contains("- coder: the coder"));
+        assert!(message_text(&request[1]).ends_with("[planner]: Here is the plan"));
+    }
+}
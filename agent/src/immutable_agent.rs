@@ -1,16 +1,23 @@
+use crate::cassette::*;
+use crate::chat_backend::*;
+use crate::conversation::*;
+use crate::error::*;
 use crate::exec_python::*;
+use crate::feedback::*;
 use crate::nous_structs::*;
+use crate::planning::*;
+use crate::run_state::*;
+use crate::schema::*;
+use crate::templates::*;
+use crate::tool_prompt::*;
+use crate::tools::*;
+use crate::trace::*;
+use crate::usage::*;
 use crate::utils::*;
-use crate::webscraper_hook::*;
-use crate::{
-    CODE_PYTHON_PROMPT, FURTER_TASK_BY_TOOLCALL_PROMPT, GROUNDING_CHECK_TEMPLATE,
-    IS_TERMINATION_PROMPT, ITERATE_CODING_FAIL_TEMPLATE, ITERATE_CODING_INCORRECT_TEMPLATE,
-    ITERATE_CODING_START_TEMPLATE, NEXT_STEP_BY_TOOLCALL_PROMPT, NEXT_STEP_PLANNING_PROMPT,
-};
-use anyhow;
 use endpoints::{
     chat::{
         ChatCompletionRequest,
+        ChatResponseFormat,
         // ChatCompletionObject,
         // ChatCompletionRequestMessage,
         // ChatCompletionRole,
@@ -20,10 +27,136 @@ use endpoints::{
     },
     // common::Usage,
 };
+use futures::future::join_all;
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 pub struct ImmutableAgent {
     pub name: String,
     pub system_prompt: String,
+    pub backend: Arc<dyn ChatBackend>,
+    pub tools: ToolRegistry,
+    pub tool_syntax: ToolSpecSyntax,
+    /// Sampling settings copied into every request; its messages are ignored.
+    pub request_template: ChatCompletionRequest,
+    threads: Mutex<HashMap<AgentRole, Conversation>>,
+    pub ctx_size: u64,
+    pub n_predict: u64,
+    /// When set, the plan is re-judged after each round of steps and may be revised up to
+    /// this many times; when `None` the initial plan runs to completion.
+    pub max_replans: Option<usize>,
+    /// Turns the ReAct loop may take before giving up.
+    pub max_react_steps: usize,
+    /// Generate-run-judge rounds `code_with_python` may take.
+    pub max_coding_iterations: usize,
+    /// Times a structured reply that doesn't match its schema is asked for again.
+    pub max_structured_retries: usize,
+    /// Prompts and message templates, keyed by the names in `TEMPLATE_VARIABLES`.
+    pub templates: TemplateSet,
+    pub feedback: Arc<dyn FeedbackProvider>,
+    /// Where `feedback` is consulted; everything else is approved without asking.
+    pub checkpoints: HashSet<Checkpoint>,
+    /// Where runs are checkpointed; without one they can't be resumed.
+    pub run_store: Option<RunStore>,
+    pub tracer: Tracer,
+    /// Shared with the agents cloned from this one, so a group chat adds up in one place.
+    pub usage: Arc<UsageMeter>,
+    /// Records or replays model replies, tool results and Python runs.
+    pub cassette: Option<Arc<Cassette>>,
+    current_run: Mutex<Option<RunState>>,
+}
+
+/// How `run` goes about a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunMode {
+    /// Plan the steps first, then execute them (`next_step_planning` + `run_plan`).
+    #[default]
+    PlanAndExecute,
+    /// Think, act and observe one tool call at a time (`react`).
+    React,
+}
+
+impl std::str::FromStr for RunMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plan" => Ok(RunMode::PlanAndExecute),
+            "react" => Ok(RunMode::React),
+            _ => Err(format!(
+                "unknown run mode '{}', expected 'plan' or 'react'",
+                s
+            )),
+        }
+    }
+}
+
+/// The last program `code_with_python` ran and what became of it.
+#[derive(Debug, Clone)]
+pub struct CodingOutcome {
+    pub code: String,
+    pub output: String,
+    pub key_points: String,
+    pub iterations: usize,
+    /// Whether the gatekeeper accepted the output, rather than the loop running out of iterations.
+    pub accepted: bool,
+}
+
+impl CodingOutcome {
+    pub fn to_tool_result(&self) -> String {
+        format!(
+            "Final code:\n```python\n{}\n```\nOutput:\n{}\nKey points: {}",
+            self.code, self.output, self.key_points
+        )
+    }
+}
+
+/// How much of each step's result the planner sees when revising the plan.
+const MAX_REPLAN_RESULT_CHARS: usize = 2000;
+
+/// Turns kept verbatim when a thread's older history is summarized.
+const KEEP_RECENT_TURNS: usize = 2;
+
+#[derive(Deserialize)]
+struct IntrinsicKnowledgeArgs {
+    task: String,
+}
+
+/// `use_intrinsic_knowledge` is routed by the agent itself rather than the registry,
+/// but the model sees it next to the registered tools.
+pub fn use_intrinsic_knowledge_spec() -> ToolSpec {
+    ToolSpec {
+        name: "use_intrinsic_knowledge".to_string(),
+        description: "Solves tasks using capabilities and knowledge obtained at training time. It is frozen at the training cut-off date and is not aware of the real-world date of its operation. This should be your first approach to problem-solving.".to_string(),
+        parameters: serde_json::json!({
+            "type": "object",
+            "properties": {
+                "task": {
+                    "type": "string",
+                    "description": "The task you receive, verbatim"
+                }
+            },
+            "required": ["task"]
+        }),
+        example: Some(serde_json::json!({ "task": "tell a joke" })),
+    }
+}
+
+/// The task of a `use_intrinsic_knowledge` call, checked against its schema.
+fn intrinsic_task(call: &NousToolCall) -> AgentResult<String> {
+    let args = call.arguments_object();
+    let IntrinsicKnowledgeArgs { task } =
+        check_args(&use_intrinsic_knowledge_spec().parameters, &args)
+            .and_then(|_| parse_args(&args))
+            .map_err(|e| AgentError::Tool {
+                tool: call.name.clone(),
+                message: e.to_string(),
+            })?;
+    Ok(task)
 }
 
 impl ImmutableAgent {
@@ -31,332 +164,1245 @@ impl ImmutableAgent {
         ImmutableAgent {
             name: name.to_string(),
             system_prompt: system_prompt.to_string(),
+            backend: default_backend(),
+            tools: ToolRegistry::builtin(),
+            tool_syntax: ToolSpecSyntax::default(),
+            request_template: ChatCompletionRequest::default(),
+            threads: Mutex::new(HashMap::new()),
+            ctx_size: 8192,
+            n_predict: 999,
+            max_replans: None,
+            max_react_steps: 10,
+            max_coding_iterations: 8,
+            max_structured_retries: 2,
+            templates: TemplateSet::builtin(),
+            feedback: Arc::new(AutoApprove),
+            checkpoints: HashSet::new(),
+            run_store: None,
+            tracer: Tracer::default(),
+            usage: Arc::new(UsageMeter::default()),
+            cassette: None,
+            current_run: Mutex::new(None),
         }
     }
 
-    pub async fn get_user_feedback(&self) -> String {
-        use std::io::{self, Write};
-        print!("User input: ");
+    pub fn with_backend(mut self, backend: Arc<dyn ChatBackend>) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn with_tools(mut self, tools: ToolRegistry) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    pub fn with_tool_syntax(mut self, tool_syntax: ToolSpecSyntax) -> Self {
+        self.tool_syntax = tool_syntax;
+        self
+    }
+
+    pub fn with_request_template(mut self, request_template: ChatCompletionRequest) -> Self {
+        self.request_template = request_template;
+        self
+    }
+
+    /// The context window the model runs with, and how much of it is kept for the reply.
+    pub fn with_context_window(mut self, ctx_size: u64, n_predict: u64) -> Self {
+        self.ctx_size = ctx_size;
+        self.n_predict = n_predict;
+        self
+    }
+
+    pub fn with_replanning(mut self, max_replans: usize) -> Self {
+        self.max_replans = Some(max_replans);
+        self
+    }
+
+    pub fn with_react_steps(mut self, max_react_steps: usize) -> Self {
+        self.max_react_steps = max_react_steps;
+        self
+    }
+
+    pub fn with_coding_iterations(mut self, max_coding_iterations: usize) -> Self {
+        self.max_coding_iterations = max_coding_iterations;
+        self
+    }
+
+    pub fn with_structured_retries(mut self, max_structured_retries: usize) -> Self {
+        self.max_structured_retries = max_structured_retries;
+        self
+    }
+
+    pub fn with_templates(mut self, templates: TemplateSet) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    /// The prompt registered under `name`, for templates rendered without variables.
+    pub fn prompt(&self, name: &str) -> String {
+        self.templates.render(name, &[])
+    }
 
-        io::stdout().flush().expect("Failed to flush stdout");
+    /// A prompt that offers `specs` to the model, in this agent's tool syntax.
+    pub fn tool_prompt(&self, name: &str, specs: &[ToolSpec]) -> String {
+        let sections = tool_sections(specs, self.tool_syntax);
+        let vars = sections
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect::<Vec<(&str, &str)>>();
+        self.templates.render(name, &vars)
+    }
 
-        let mut input = String::new();
+    pub fn with_feedback(
+        mut self,
+        feedback: Arc<dyn FeedbackProvider>,
+        checkpoints: &[Checkpoint],
+    ) -> Self {
+        self.feedback = feedback;
+        self.checkpoints = checkpoints.iter().copied().collect();
+        self
+    }
 
-        io::stdin()
-            .read_line(&mut input)
-            .expect("Failed to read line");
+    pub fn with_run_store(mut self, run_store: RunStore) -> Self {
+        self.run_store = Some(run_store);
+        self
+    }
+
+    /// Stops runs cleanly, with what they have so far, once they spend `budget`.
+    pub fn with_budget(mut self, budget: Budget) -> Self {
+        self.usage = Arc::new(UsageMeter::new(budget));
+        self
+    }
+
+    /// Records every reply of the backend set so far, every tool result and every Python
+    /// run to `cassette`, or serves them from it when it is replaying.
+    pub fn with_cassette(mut self, cassette: Arc<Cassette>) -> Self {
+        self.backend = Arc::new(CassetteBackend::new(self.backend, cassette.clone()));
+        self.cassette = Some(cassette);
+        self
+    }
+
+    /// Traces every request to the backend set so far, and every step, tool call and
+    /// Python run this agent makes.
+    pub fn with_tracer(mut self, tracer: Tracer) -> Self {
+        self.backend = Arc::new(TracedBackend::new(self.backend, tracer.clone()));
+        self.tracer = tracer;
+        self
+    }
 
-        if let Some('\n') = input.chars().next_back() {
-            input.pop();
+    /// A new agent with the same backend, tools and settings but its own name, system
+    /// prompt and threads.
+    pub fn clone_as(&self, name: &str, system_prompt: &str) -> Self {
+        ImmutableAgent {
+            name: name.to_string(),
+            system_prompt: system_prompt.to_string(),
+            backend: self.backend.clone(),
+            tools: self.tools.clone(),
+            tool_syntax: self.tool_syntax,
+            request_template: request_from_template(&self.request_template, vec![]),
+            threads: Mutex::new(HashMap::new()),
+            ctx_size: self.ctx_size,
+            n_predict: self.n_predict,
+            max_replans: self.max_replans,
+            max_react_steps: self.max_react_steps,
+            max_coding_iterations: self.max_coding_iterations,
+            max_structured_retries: self.max_structured_retries,
+            templates: self.templates.clone(),
+            feedback: self.feedback.clone(),
+            checkpoints: self.checkpoints.clone(),
+            run_store: self.run_store.clone(),
+            tracer: self.tracer.clone(),
+            usage: self.usage.clone(),
+            cassette: self.cassette.clone(),
+            current_run: Mutex::new(None),
         }
-        if let Some('\r') = input.chars().next_back() {
-            input.pop();
+    }
+
+    /// A snapshot of the run in progress, or of the last one.
+    pub fn current_run(&self) -> Option<RunState> {
+        self.current_run.lock().unwrap().clone()
+    }
+
+    /// The id of the run in progress, or of the last one.
+    pub fn current_run_id(&self) -> Option<String> {
+        self.current_run
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|run| run.run_id.clone())
+    }
+
+    /// Applies `update` to the current run, if there is one, and saves it to `run_store`.
+    /// A failed save is reported but doesn't stop the run.
+    fn record_progress(&self, update: impl FnOnce(&mut RunState)) {
+        let mut current = self.current_run.lock().unwrap();
+        let Some(run) = current.as_mut() else {
+            return;
+        };
+        update(run);
+        if let Some(store) = &self.run_store {
+            if let Err(e) = store.save(run) {
+                println!("[WARN] could not checkpoint run {}: {}", run.run_id, e);
+            }
         }
+    }
+
+    fn record_llm_call(&self, role: AgentRole, input: &str, reply: &str) {
+        self.record_progress(|run| {
+            run.events.push(RunEvent::LlmCall {
+                role,
+                input: input.to_string(),
+                reply: reply.to_string(),
+            })
+        });
+    }
 
-        if input == "stop" {
-            std::process::exit(0);
+    /// Outputs and completion order of the steps of `plan` the current run already finished.
+    fn finished_steps(&self, plan: &Plan) -> (HashMap<String, String>, Vec<String>) {
+        match self.current_run.lock().unwrap().as_ref() {
+            Some(run) if run.plan.as_ref() == Some(plan) => {
+                (run.outputs.clone(), run.completed.clone())
+            }
+            _ => (HashMap::new(), Vec::new()),
         }
-        return input;
     }
 
-    pub async fn furter_task_by_toolcall(
+    fn trace_step(&self, span: u64, step: &PlanStep, result: &AgentResult<String>, start: Instant) {
+        self.tracer.record_span(
+            span,
+            Some(&step.id),
+            TraceEvent::Step {
+                description: step.description.clone(),
+                output: result.as_ref().ok().cloned(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+                duration_ms: elapsed_ms(start),
+            },
+        );
+    }
+
+    /// `run_python_capture`, through the cassette if there is one.
+    fn run_python(&self, code: &str) -> AgentResult<Result<String, String>> {
+        let Some(cassette) = &self.cassette else {
+            return Ok(run_python_capture(code));
+        };
+        let request = Cassette::python_request(code);
+        if cassette.is_replaying() {
+            return cassette
+                .lookup("python", &request)
+                .and_then(|r| serde_json::from_value(r).ok())
+                .ok_or_else(|| {
+                    AgentError::Execution(
+                        "the cassette has no recorded run of this code".to_string(),
+                    )
+                });
+        }
+        let run = run_python_capture(code);
+        cassette.store("python", request, serde_json::json!(run));
+        Ok(run)
+    }
+
+    /// A snapshot of the thread `role` has accumulated so far.
+    pub fn thread(&self, role: AgentRole) -> Option<Conversation> {
+        self.threads.lock().unwrap().get(&role).cloned()
+    }
+
+    /// Stores `conversation` as `role`'s thread, unless the role sends no history, in
+    /// which case there's nothing worth keeping.
+    fn keep_thread(&self, role: AgentRole, conversation: Conversation) {
+        if role.default_scope() != HistoryScope::None {
+            self.threads.lock().unwrap().insert(role, conversation);
+        }
+    }
+
+    pub fn reset_threads(&self) {
+        self.threads.lock().unwrap().clear();
+    }
+
+    /// `chat_completions_partial`, counted against the budget as a call made by `role`.
+    pub async fn complete_partial(
         &self,
-        chat_request: &mut ChatCompletionRequest,
+        role: &str,
+        conversation: &mut Conversation,
+        user_input: &str,
+    ) -> AgentResult<NousResponseMessage> {
+        self.complete_with(role, &self.request_template, conversation, user_input)
+            .await
+    }
+
+    async fn complete_with(
+        &self,
+        role: &str,
+        request_template: &ChatCompletionRequest,
+        conversation: &mut Conversation,
+        user_input: &str,
+    ) -> AgentResult<NousResponseMessage> {
+        self.usage.check()?;
+        let output = chat_completions_partial(
+            self.backend.as_ref(),
+            request_template,
+            conversation,
+            user_input,
+        )
+        .await?;
+        self.usage.record(role, &output.usage);
+        Ok(output)
+    }
+
+    /// `chat_completions_full`, counted against the budget as a call made by `role`.
+    pub async fn complete_full(
+        &self,
+        role: &str,
+        system_prompt: &str,
+        user_input: &str,
+    ) -> AgentResult<NousResponseMessage> {
+        self.usage.check()?;
+        let output = chat_completions_full(
+            self.backend.as_ref(),
+            &self.request_template,
+            system_prompt,
+            user_input,
+        )
+        .await?;
+        self.usage.record(role, &output.usage);
+        Ok(output)
+    }
+
+    /// Sends `input` on `role`'s own thread under `system_prompt`, then records the exchange.
+    pub async fn chat_as(
+        &self,
+        role: AgentRole,
+        system_prompt: &str,
         input: &str,
-    ) -> Option<String> {
-        let output: NousResponseMessage =
-            chat_completions_full(chat_request, &FURTER_TASK_BY_TOOLCALL_PROMPT, input)
-                .await
-                .expect("Failed to generate reply");
+    ) -> AgentResult<NousResponseMessage> {
+        let (mut conversation, input) = self.role_thread(role, system_prompt, input).await?;
 
-        match &output.content {
-            NousContent::Text(t) => {
-                return Some(t.to_string());
-            }
-            NousContent::NousToolCall(call) => {
-                let args = call.clone().arguments.unwrap_or_default();
-
-                let res = match call.name.as_str() {
-                    "get_webpage_text" => {
-                        let url = args
-                            .get("url")
-                            .ok_or_else(|| anyhow::anyhow!("Missing 'url' argument"))
-                            .ok()?
-                            .to_string();
-
-                        get_webpage_text(url).await.ok()?
-                    }
-                    "search_with_bing" => {
-                        let query = args
-                            .get("query")
-                            .ok_or_else(|| anyhow::anyhow!("Missing 'query' argument"))
-                            .ok()?
-                            .to_string();
-                        search_with_bing(&query).await.ok()?
-                    }
-                    "code_with_python" => {
-                        let key_points = args
-                            .get("key_points")
-                            .ok_or_else(|| anyhow::anyhow!("Missing 'key_points' argument"))
-                            .ok()?
-                            .to_string();
-                        // let _ = self.code_with_python(&key_points).await;
-
-                        String::from("code is being generated")
-                    }
-                    _ => {
-                        return None;
-                    }
-                };
-                Some(res)
+        let output = self
+            .complete_partial(role.label(), &mut conversation, &input)
+            .await?;
+        self.record_llm_call(role, &input, &output.content_to_string());
+
+        self.keep_thread(role, conversation);
+        Ok(output)
+    }
+
+    /// Asks `role` on its own thread for a reply of type `T`, like `chat_as`, in the
+    /// backend's JSON mode if it has one. A reply that doesn't match `T::schema()` is sent
+    /// back with the validation error up to `max_structured_retries` times; only the
+    /// accepted exchange is kept on the thread.
+    pub async fn structured_completion<T: StructuredReply>(
+        &self,
+        role: AgentRole,
+        system_prompt: &str,
+        input: &str,
+    ) -> AgentResult<T> {
+        let (mut conversation, input) = self.role_thread(role, system_prompt, input).await?;
+        let mut request_template = request_from_template(&self.request_template, vec![]);
+        if self.backend.supports_json_mode() {
+            request_template.response_format = Some(ChatResponseFormat {
+                ty: "json_object".to_string(),
+            });
+        }
+
+        // retries see the original input and the rejected reply even on roles that keep no
+        // history
+        let mut attempt = conversation.exchange();
+        let mut prompt = input.clone();
+        let mut error = String::new();
+        for _ in 0..=self.max_structured_retries {
+            let reply = self
+                .complete_with(role.label(), &request_template, &mut attempt, &prompt)
+                .await?
+                .content_to_string();
+            self.record_llm_call(role, &prompt, &reply);
+
+            match check_reply::<T>(&reply) {
+                Ok(parsed) => {
+                    conversation.record_turn(&input, &reply);
+                    self.keep_thread(role, conversation);
+                    return Ok(parsed);
+                }
+                Err(e) => {
+                    println!(
+                        "[WARN] The {} reply did not match the expected format: {}",
+                        role.label(),
+                        e
+                    );
+                    let schema = serde_json::to_string_pretty(&T::schema()).unwrap_or_default();
+                    prompt = self
+                        .templates
+                        .render("structured_retry", &[("error", &e), ("schema", &schema)]);
+                    error = e;
+                }
             }
         }
+        Err(AgentError::Parse(format!(
+            "the {} reply did not match the expected format after {} attempts: {}",
+            role.label(),
+            self.max_structured_retries + 1,
+            error
+        )))
     }
 
-    pub async fn next_step_by_toolcall(
+    /// `role`'s thread under `system_prompt`, and `input` with the summary of any history
+    /// compressed to make room for it.
+    async fn role_thread(
         &self,
-        chat_request: &mut ChatCompletionRequest,
+        role: AgentRole,
+        system_prompt: &str,
         input: &str,
-    ) -> Option<String> {
-        let output: NousResponseMessage =
-            chat_completions_full(chat_request, &NEXT_STEP_BY_TOOLCALL_PROMPT, &input)
-                .await
-                .expect("Failed to generate reply");
+    ) -> AgentResult<(Conversation, String)> {
+        let mut conversation = self
+            .thread(role)
+            .unwrap_or_else(|| Conversation::for_role(role, system_prompt));
+        conversation.set_system_prompt(system_prompt);
 
-        match &output.content {
-            NousContent::Text(_) => {
-                todo!();
+        let input = match self.compress_if_needed(&mut conversation, input).await? {
+            Some(summary) => format!(
+                "Summary of the earlier conversation:\n{}\n\n{}",
+                summary, input
+            ),
+            None => input.to_string(),
+        };
+        Ok((conversation, input))
+    }
+
+    /// When sending `next_input` on `conversation` would come close to filling the context
+    /// window, summarizes all but the last few turns with the "summarize" prompt and
+    /// drops them from the thread. The summary is returned so the caller can fold it into
+    /// its next prompt.
+    pub async fn compress_if_needed(
+        &self,
+        conversation: &mut Conversation,
+        next_input: &str,
+    ) -> AgentResult<Option<String>> {
+        let prompt_budget = self.ctx_size.saturating_sub(self.n_predict);
+        if conversation.projected_tokens(next_input) < prompt_budget * 9 / 10 {
+            return Ok(None);
+        }
+
+        let older = conversation.drain_older_turns(KEEP_RECENT_TURNS);
+        if older.is_empty() {
+            return Ok(None);
+        }
+
+        let summarize_prompt = self.prompt("summarize");
+        let history_text = older
+            .iter()
+            .map(message_text)
+            .collect::<Vec<String>>()
+            .join("\n\n");
+        // the summarizer has the same window; keep the most recent part if the history is larger
+        let max_chars = (prompt_budget.saturating_sub(estimate_tokens(&summarize_prompt)) * 4 * 9
+            / 10) as usize;
+        let skip = history_text.chars().count().saturating_sub(max_chars);
+        let history_text = history_text.chars().skip(skip).collect::<String>();
+
+        let summary = self
+            .complete_full("summarizer", &summarize_prompt, &history_text)
+            .await?;
+
+        Ok(Some(summary.content_to_string()))
+    }
+
+    /// Registered tools plus `use_intrinsic_knowledge`, as offered to the dispatcher and planner.
+    fn dispatcher_tool_specs(&self) -> Vec<ToolSpec> {
+        let mut specs = vec![use_intrinsic_knowledge_spec()];
+        specs.extend(self.tools.specs());
+        specs
+    }
+
+    /// Consults `feedback` at `checkpoint` if it is enabled, and approves otherwise.
+    /// `Feedback::Abort` comes back as `AgentError::Aborted`.
+    pub async fn review(&self, checkpoint: Checkpoint, content: &str) -> AgentResult<Feedback> {
+        if !self.checkpoints.contains(&checkpoint) {
+            return Ok(Feedback::Approve);
+        }
+        match self.feedback.review(checkpoint, content).await? {
+            Feedback::Abort => Err(AgentError::Aborted),
+            feedback => Ok(feedback),
+        }
+    }
+
+    /// Routes one step to a tool, or answers it directly when the router picks
+    /// `use_intrinsic_knowledge`, as plans often suggest. A call that fails, e.g. on
+    /// arguments that don't match the tool's schema, is sent back to the router with the
+    /// error up to `max_structured_retries` times; after that the error becomes the step's
+    /// result, so the run goes on.
+    pub async fn furter_task_by_toolcall(&self, input: &str) -> AgentResult<String> {
+        let system_prompt = self.tool_prompt("tool_router", &self.dispatcher_tool_specs());
+        let mut prompt = input.to_string();
+        let mut attempt = 0;
+        loop {
+            let output: NousResponseMessage = self
+                .chat_as(AgentRole::ToolRouter, &system_prompt, &prompt)
+                .await?;
+
+            let calls = match &output.content {
+                NousContent::Text(t) => return Ok(t.to_string()),
+                NousContent::NousToolCalls(calls) => calls,
+            };
+            // the step's own input, not the call's `task`, carries the results it builds on
+            if calls.iter().any(|c| c.name == "use_intrinsic_knowledge") {
+                return self.answer_directly(input).await;
+            }
+            match self.run_tool_calls(calls).await {
+                Err(e @ AgentError::Tool { .. }) if attempt < self.max_structured_retries => {
+                    println!("[WARN] tool call failed, asking the router again: {}", e);
+                    // the router keeps no history, so the retry carries the task and the call
+                    prompt = self.templates.render(
+                        "tool_retry",
+                        &[
+                            ("task", input),
+                            ("calls", &output.content_to_string()),
+                            ("error", &e.to_string()),
+                        ],
+                    );
+                    attempt += 1;
+                }
+                Err(e @ AgentError::Tool { .. }) => return Ok(format!("Error: {}", e)),
+                result => return result,
+            }
+        }
+    }
+
+    /// Runs `calls` concurrently and joins their results in call order. A call that fails
+    /// is reported in its place as "Error: ...", so the others still count; only an error
+    /// that ends the run, or the failure of every call, is returned as an error.
+    pub async fn run_tool_calls(&self, calls: &[NousToolCall]) -> AgentResult<String> {
+        let mut results = self.tools.dispatch_all(self, calls).await;
+        let fatal = results
+            .iter()
+            .position(|result| result.as_ref().is_err_and(AgentError::ends_run));
+        let all_failed = !results.is_empty() && results.iter().all(Result::is_err);
+        if let Some(pos) = fatal.or(all_failed.then_some(0)) {
+            return results.swap_remove(pos);
+        }
+        let results = results
+            .into_iter()
+            .map(|result| result.unwrap_or_else(|e| format!("Error: {}", e)))
+            .collect::<Vec<String>>();
+        self.record_progress(|run| {
+            for (call, result) in calls.iter().zip(&results) {
+                run.events.push(RunEvent::ToolResult {
+                    tool: call.name.clone(),
+                    arguments: call.arguments.clone(),
+                    result: result.clone(),
+                });
             }
-            NousContent::NousToolCall(call) => {
-                let args = call.clone().arguments.unwrap_or_default();
+        });
 
-                let res = match call.name.as_str() {
-                    "use_intrinsic_knowledge" => {
-                        let task = args
-                            .get("task")
-                            .ok_or_else(|| anyhow::anyhow!("Missing 'task' argument"))
-                            .ok()?
-                            .to_string();
+        Ok(format_tool_results(calls, &results))
+    }
 
-                        let steps_vec = self.next_step_planning(chat_request, &task).await;
+    /// Lets the model pick a tool for `input`. A plain-text reply is taken as the answer;
+    /// `use_intrinsic_knowledge` hands the task to the planner and stepper.
+    pub async fn next_step_by_toolcall(&self, input: &str) -> AgentResult<String> {
+        let system_prompt = self.tool_prompt("dispatcher", &self.dispatcher_tool_specs());
+        let output: NousResponseMessage = self
+            .chat_as(AgentRole::ToolRouter, &system_prompt, input)
+            .await?;
 
-                        let _ = self.stepper(chat_request, &steps_vec).await;
-                        std::process::exit(0);
-                    }
-                    "get_webpage_text" => {
-                        let url = args
-                            .get("url")
-                            .ok_or_else(|| anyhow::anyhow!("Missing 'url' argument"))
-                            .ok()?
-                            .to_string();
-
-                        get_webpage_text(url).await.ok()?
-                    }
-                    "search_with_bing" => {
-                        let query = args
-                            .get("query")
-                            .ok_or_else(|| anyhow::anyhow!("Missing 'query' argument"))
-                            .ok()?
-                            .to_string();
-                        search_with_bing(&query).await.ok()?
-                    }
-                    "code_with_python" => {
-                        let key_points = args
-                            .get("key_points")
-                            .ok_or_else(|| anyhow::anyhow!("Missing 'key_points' argument"))
-                            .ok()?
-                            .to_string();
-                        let _ = self.code_with_python(chat_request, &key_points).await;
-
-                        String::from("code is being generated")
-                    }
+        match &output.content {
+            NousContent::Text(t) => Ok(t.to_string()),
+            NousContent::NousToolCalls(calls) => {
+                match calls.iter().find(|c| c.name == "use_intrinsic_knowledge") {
+                    Some(call) => {
+                        let task = intrinsic_task(call)?;
+
+                        self.tracer.planner_decision(
+                            "use_intrinsic_knowledge",
+                            serde_json::json!({ "task": task }),
+                        );
+                        let plan = self.reviewed_plan(&task).await?;
 
-                    _ => {
-                        return None;
+                        self.run_plan(&task, &plan).await
                     }
-                };
-                Some(res)
+                    None => self.run_tool_calls(calls).await,
+                }
             }
         }
     }
-    pub async fn next_step_planning(
-        &self,
-        chat_request: &mut ChatCompletionRequest,
-        input: &str,
-    ) -> Vec<String> {
-        let output: NousResponseMessage =
-            chat_completions_full(chat_request, &NEXT_STEP_PLANNING_PROMPT, input)
-                .await
-                .expect("Failed to generate reply");
 
-        match &output.content {
-            NousContent::Text(_out) => {
-                println!("{:?}\n\n", _out.clone());
-                let mut res = parse_planning_steps(_out);
-                res.reverse();
-                res
+    /// Answers `task` from the model's own knowledge in one call under the agent's system
+    /// prompt; what a step gets when the router picks `use_intrinsic_knowledge`, with the
+    /// step input as `task`.
+    async fn answer_directly(&self, task: &str) -> AgentResult<String> {
+        let answer = self
+            .complete_full(AgentRole::ToolRouter.label(), &self.system_prompt, task)
+            .await?
+            .content_to_string();
+        self.record_llm_call(AgentRole::ToolRouter, task, &answer);
+        Ok(answer)
+    }
+
+    pub async fn next_step_planning(&self, input: &str) -> AgentResult<Plan> {
+        let system_prompt = self.tool_prompt("planning", &self.dispatcher_tool_specs());
+        let reply = self
+            .structured_completion::<PlanningReply>(AgentRole::Planner, &system_prompt, input)
+            .await?;
+        show_thought_process("Planner", &reply.my_thought_process);
+
+        let plan = reply.plan;
+        if plan.is_empty() {
+            return Err(AgentError::Parse(
+                "the planner's reply has no steps to take".to_string(),
+            ));
+        }
+        plan.validate().map_err(AgentError::Parse)?;
+        self.tracer.planner_decision(
+            "plan",
+            serde_json::json!({
+                "thought_process": reply.my_thought_process,
+                "steps": plan.steps,
+            }),
+        );
+        Ok(plan)
+    }
+
+    /// Runs `plan`, starting each step as soon as the steps it depends on are done, so
+    /// independent branches run concurrently. Returns the output of the final step, or of
+    /// every final step when the plan ends in several branches.
+    pub async fn stepper(&self, plan: &Plan) -> AgentResult<String> {
+        if plan.is_empty() {
+            return Err(AgentError::Parse(
+                "the plan has no task to handle".to_string(),
+            ));
+        }
+
+        let (mut outputs, completed) = self.finished_steps(plan);
+        let mut done = completed.into_iter().collect::<HashSet<String>>();
+        let mut started = done.clone();
+        let mut running = FuturesUnordered::new();
+        loop {
+            for step in plan.ready_steps(&done, &started) {
+                started.insert(step.id.clone());
+                let input = step_input(step, &outputs);
+                println!("Step {}: {}", step.id, step.description);
+                self.record_progress(|run| run.step_started(&step.id));
+                running.push(async move {
+                    let start = Instant::now();
+                    let (span, result) =
+                        in_span(Some(&step.id), self.furter_task_by_toolcall(&input)).await;
+                    self.trace_step(span, step, &result, start);
+                    (step.id.clone(), result)
+                });
+            }
+
+            match running.next().await {
+                Some((id, result)) => {
+                    let output = result?;
+                    self.record_progress(|run| run.step_finished(&id, &output));
+                    outputs.insert(id.clone(), output);
+                    done.insert(id);
+                }
+                None => break,
             }
-            _ => unreachable!(),
         }
+
+        Ok(final_output(plan, &outputs))
     }
 
-    pub async fn stepper(
+    /// Works on `task` with the strategy chosen by `mode`, checkpointing the run to
+    /// `run_store` as it goes. Each run starts with fresh role threads.
+    pub async fn run(&self, task: &str, mode: RunMode) -> AgentResult<String> {
+        self.reset_threads();
+        let run = RunState::new(task, mode);
+        if let Some(store) = &self.run_store {
+            println!(
+                "Run {} (checkpointed to {})",
+                run.run_id,
+                store.path(&run.run_id).display()
+            );
+        }
+        *self.current_run.lock().unwrap() = Some(run);
+        self.record_progress(|_| {});
+        self.continue_run().await
+    }
+
+    /// Picks the saved run `run_id` up from its last checkpoint. Finished steps are not
+    /// run again; steps that were in flight start over. React runs start over from the task.
+    pub async fn resume(&self, run_id: &str) -> AgentResult<String> {
+        let store = self
+            .run_store
+            .as_ref()
+            .ok_or_else(|| AgentError::Resume("runs are not being checkpointed".to_string()))?;
+        let run = store.load(run_id)?;
+        *self.current_run.lock().unwrap() = Some(run);
+        self.continue_run().await
+    }
+
+    /// Runs step `step_id` of the saved run `run_id` again, with `edited` as its new
+    /// description if given, along with every step that builds on it.
+    pub async fn rerun_from(
         &self,
-        chat_request: &mut ChatCompletionRequest,
-        task_vec: &Vec<String>,
-    ) -> anyhow::Result<String> {
-        let mut task_vec = task_vec.clone();
-        let mut initial_input = match task_vec.pop() {
-            Some(s) => s,
-            None => {
-                return Err(anyhow::Error::msg("no task to handle"));
-            }
+        run_id: &str,
+        step_id: &str,
+        edited: Option<&str>,
+    ) -> AgentResult<String> {
+        let store = self
+            .run_store
+            .as_ref()
+            .ok_or_else(|| AgentError::Resume("runs are not being checkpointed".to_string()))?;
+        let mut run = store.load(run_id)?;
+        run.rewind_to(step_id, edited)?;
+        store.save(&run)?;
+        *self.current_run.lock().unwrap() = Some(run);
+        self.continue_run().await
+    }
+
+    /// Drives the current run from its saved phase to `Done`, tracing under its id.
+    /// Running out of budget ends it with the output of the steps finished so far; the
+    /// run is left resumable.
+    async fn continue_run(&self) -> AgentResult<String> {
+        self.usage.reset();
+        let scope = TraceScope {
+            run_id: self.current_run_id(),
+            ..TraceScope::default()
         };
-        let mut res = String::new();
+        match in_scope(scope, self.drive_run()).await {
+            Err(e @ AgentError::BudgetExhausted { .. }) => Ok(self.partial_result(&e)),
+            result => result,
+        }
+    }
+
+    fn partial_result(&self, reason: &AgentError) -> String {
+        let current = self.current_run.lock().unwrap();
+        let finished = current
+            .as_ref()
+            .map(|run| {
+                run.completed
+                    .iter()
+                    .filter_map(|id| Some(format!("[{}] {}", id, run.outputs.get(id)?)))
+                    .collect::<Vec<String>>()
+            })
+            .unwrap_or_default();
+        if finished.is_empty() {
+            format!("Stopped early, {}, before any step finished.", reason)
+        } else {
+            format!(
+                "Stopped early, {}. Partial result:\n\n{}",
+                reason,
+                finished.join("\n\n")
+            )
+        }
+    }
+
+    /// A rejected answer sends the task round again with the reviewer's comment.
+    async fn drive_run(&self) -> AgentResult<String> {
+        let run = self
+            .current_run
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| AgentError::Resume("there is no run to continue".to_string()))?;
+        if let RunPhase::Done { answer } = run.phase {
+            return Ok(answer);
+        }
+
+        let (task, mode) = (run.task, run.mode);
+        let mut input = run.input;
+        let mut plan = run.plan;
         loop {
-            res = self
-                .furter_task_by_toolcall(chat_request, &initial_input)
-                .await
-                .unwrap();
-            initial_input = match task_vec.pop() {
-                Some(s) => format!(
-                    "Here is the result from previous step: {}, here is the next task: {}",
-                    res, s
-                ),
-                None => {
-                    break;
+            let answer = match mode {
+                RunMode::PlanAndExecute => {
+                    let plan = match plan.take() {
+                        Some(plan) => plan,
+                        None => {
+                            let plan = self.reviewed_plan(&input).await?;
+                            self.record_progress(|run| {
+                                run.plan = Some(plan.clone());
+                                run.phase = RunPhase::Executing { steps: vec![] };
+                            });
+                            plan
+                        }
+                    };
+                    self.run_plan(&input, &plan).await?
+                }
+                RunMode::React => self.react(&input).await?,
+            };
+
+            self.record_progress(|run| run.phase = RunPhase::Verifying);
+            let answer = match self.review(Checkpoint::BeforeAnswer, &answer).await? {
+                Feedback::Approve => answer,
+                Feedback::Edit(edited) => edited,
+                Feedback::Reject(comment) => {
+                    input = format!(
+                        "{}\n\nA previous answer was rejected.\nPrevious answer: {}\nReviewer's comment: {}",
+                        task, answer, comment
+                    );
+                    self.record_progress(|run| run.restart(&input));
+                    continue;
                 }
+                Feedback::Abort => return Err(AgentError::Aborted),
             };
+            self.record_progress(|run| {
+                run.phase = RunPhase::Done {
+                    answer: answer.clone(),
+                }
+            });
+            return Ok(answer);
+        }
+    }
+
+    /// A plan for `task` the reviewer has approved or edited; a rejected plan is drawn up
+    /// again with the reviewer's comment.
+    pub async fn reviewed_plan(&self, task: &str) -> AgentResult<Plan> {
+        let mut plan = self.next_step_planning(task).await?;
+        loop {
+            let shown =
+                serde_json::to_string_pretty(&serde_json::json!({ "steps_to_take": plan.steps }))
+                    .unwrap_or_default();
+
+            match self.review(Checkpoint::AfterPlan, &shown).await? {
+                Feedback::Approve => return Ok(plan),
+                Feedback::Edit(edited) => {
+                    let edited = match parse_reply::<PlanningReply>(&edited) {
+                        Ok(reply) if !reply.plan.is_empty() => reply.plan,
+                        Ok(_) => {
+                            println!("The edited plan has no steps; please try again");
+                            continue;
+                        }
+                        Err(e) => {
+                            println!("Could not read the edited plan ({}); please try again", e);
+                            continue;
+                        }
+                    };
+                    match edited.validate() {
+                        Ok(()) => return Ok(edited),
+                        Err(e) => println!("The edited plan is invalid: {}", e),
+                    }
+                }
+                Feedback::Reject(comment) => {
+                    plan = self
+                        .next_step_planning(&format!(
+                            "{}\n\nThe user rejected this plan:\n{}\nTheir comment: {}",
+                            task, shown, comment
+                        ))
+                        .await?;
+                }
+                Feedback::Abort => return Err(AgentError::Aborted),
+            }
+        }
+    }
+
+    /// The ReAct loop: each turn the model writes a thought and calls a tool, and the result
+    /// is sent back as an observation, until it gives a final answer or `max_react_steps` runs out.
+    pub async fn react(&self, task: &str) -> AgentResult<String> {
+        let system_prompt = self.tool_prompt("react", &self.tools.specs());
+        let mut conversation = Conversation::for_role(AgentRole::Reasoner, &system_prompt);
+        let mut user_prompt = format!("Task: {}", task);
+
+        for n in 1..=self.max_react_steps {
+            if let Some(summary) = self
+                .compress_if_needed(&mut conversation, &user_prompt)
+                .await?
+            {
+                user_prompt = format!(
+                    "Task: {}\n\nSummary of the earlier turns:\n{}\n\n{}",
+                    task, summary, user_prompt
+                );
+            }
+
+            let reply = self
+                .complete_partial(AgentRole::Reasoner.label(), &mut conversation, &user_prompt)
+                .await?;
+            self.record_llm_call(
+                AgentRole::Reasoner,
+                &user_prompt,
+                &reply.content_to_string(),
+            );
+
+            let (thought, calls) = match reply.content {
+                NousContent::NousToolCalls(calls) => (reply.commentary, Some(calls)),
+                NousContent::Text(text) => (text.trim().to_string(), None),
+            };
+            println!("Turn {}: {}", n, thought);
+
+            user_prompt = match calls {
+                Some(calls) => {
+                    let observation = match self.run_tool_calls(&calls).await {
+                        Ok(result) => result,
+                        Err(e) if e.ends_run() => return Err(e),
+                        Err(e) => format!("Error: {}", e),
+                    };
+                    println!("Observation: {}", observation);
+                    format!("Observation: {}", observation)
+                }
+                None => match thought.find("Final Answer:") {
+                    Some(pos) => {
+                        let answer = thought[pos + "Final Answer:".len()..].trim().to_string();
+                        self.threads
+                            .lock()
+                            .unwrap()
+                            .insert(AgentRole::Reasoner, conversation);
+                        return Ok(answer);
+                    }
+                    None => "Observation: no function was called. Either call one function or write \"Final Answer:\" followed by the answer.".to_string(),
+                },
+            };
+        }
+
+        self.threads
+            .lock()
+            .unwrap()
+            .insert(AgentRole::Reasoner, conversation);
+        Err(AgentError::LimitExceeded {
+            what: "ReAct step".to_string(),
+            limit: self.max_react_steps,
+        })
+    }
+
+    /// Runs `plan` for `goal`, re-planning along the way if `max_replans` is set.
+    pub async fn run_plan(&self, goal: &str, plan: &Plan) -> AgentResult<String> {
+        match self.max_replans {
+            Some(max_replans) => self.adaptive_stepper(goal, plan, max_replans).await,
+            None => self.stepper(plan).await,
+        }
+    }
+
+    /// Runs `plan` one round of ready steps at a time. After each round the gatekeeper
+    /// judges the results against `goal` and ends the run once it is met; otherwise the
+    /// planner may revise, insert or drop the remaining steps, at most `max_replans` times.
+    /// A step that fails is reported to both as an "Error: ..." result rather than ending the run.
+    pub async fn adaptive_stepper(
+        &self,
+        goal: &str,
+        plan: &Plan,
+        max_replans: usize,
+    ) -> AgentResult<String> {
+        if plan.is_empty() {
+            return Err(AgentError::Parse(
+                "the plan has no task to handle".to_string(),
+            ));
+        }
+
+        let mut plan = plan.clone();
+        let (mut outputs, finished) = self.finished_steps(&plan);
+        let mut done = finished.iter().cloned().collect::<HashSet<String>>();
+        let mut completed = finished
+            .iter()
+            .filter_map(|id| plan.get(id).cloned())
+            .collect::<Vec<PlanStep>>();
+        let mut replans = self
+            .current_run
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |run| run.replans);
+        loop {
+            let ready = plan
+                .ready_steps(&done, &HashSet::new())
+                .into_iter()
+                .cloned()
+                .collect::<Vec<PlanStep>>();
+            if ready.is_empty() {
+                break;
+            }
+
+            let results = join_all(ready.iter().map(|step| {
+                let input = step_input(step, &outputs);
+                println!("Step {}: {}", step.id, step.description);
+                self.record_progress(|run| run.step_started(&step.id));
+                async move {
+                    let start = Instant::now();
+                    let (span, result) =
+                        in_span(Some(&step.id), self.furter_task_by_toolcall(&input)).await;
+                    self.trace_step(span, step, &result, start);
+                    result
+                }
+            }))
+            .await;
+            let mut latest = Vec::new();
+            for (step, result) in ready.into_iter().zip(results) {
+                let output = match result {
+                    Ok(output) => output,
+                    Err(e) if e.ends_run() => return Err(e),
+                    Err(e) => {
+                        println!("Step {} failed: {}", step.id, e);
+                        format!("Error: {}", e)
+                    }
+                };
+                self.record_progress(|run| run.step_finished(&step.id, &output));
+                latest.push(format!("[{}] {}", step.id, output));
+                outputs.insert(step.id.clone(), output);
+                done.insert(step.id.clone());
+                completed.push(step);
+            }
+
+            let latest = latest.join("\n\n");
+            self.record_progress(|run| run.phase = RunPhase::Verifying);
+            let (goal_met, _) = self._is_termination(&latest, goal).await?;
+            if goal_met {
+                println!("Goal met after {} steps", completed.len());
+                return Ok(final_output(&plan, &outputs));
+            }
+
+            if replans >= max_replans {
+                continue;
+            }
+            replans += 1;
+            let remaining = plan
+                .steps
+                .iter()
+                .filter(|s| !done.contains(&s.id))
+                .cloned()
+                .collect::<Vec<PlanStep>>();
+            if let Some(revised) = self.replan(goal, &completed, &outputs, &remaining).await? {
+                let mut steps = completed.clone();
+                steps.extend(revised.steps);
+                let revised = Plan::new(steps);
+                match revised.validate() {
+                    Ok(()) => plan = revised,
+                    Err(e) => println!("Ignoring revised plan: {}", e),
+                }
+            }
+            self.record_progress(|run| {
+                run.plan = Some(plan.clone());
+                run.replans = replans;
+            });
+        }
+
+        Ok(final_output(&plan, &outputs))
+    }
+
+    /// Asks the planner for the remaining steps given what has been done so far.
+    /// `None` if its reply could not be read, in which case the plan is left as it was.
+    async fn replan(
+        &self,
+        goal: &str,
+        completed: &[PlanStep],
+        outputs: &HashMap<String, String>,
+        remaining: &[PlanStep],
+    ) -> AgentResult<Option<Plan>> {
+        let system_prompt = self.tool_prompt("replanning", &self.dispatcher_tool_specs());
+        let history = completed
+            .iter()
+            .map(|step| {
+                let output = outputs
+                    .get(&step.id)
+                    .map(|s| s.as_str())
+                    .unwrap_or_default();
+                format!(
+                    "[{}] {}\nResult: {}",
+                    step.id,
+                    step.description,
+                    output
+                        .chars()
+                        .take(MAX_REPLAN_RESULT_CHARS)
+                        .collect::<String>()
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n\n");
+        let user_prompt = format!(
+            "Goal: {}\n\nSteps done so far:\n{}\n\nSteps still planned:\n{}",
+            goal,
+            history,
+            serde_json::to_string(remaining).unwrap_or_default()
+        );
+
+        let reply = match self
+            .structured_completion::<PlanningReply>(
+                AgentRole::Planner,
+                &system_prompt,
+                &user_prompt,
+            )
+            .await
+        {
+            Err(AgentError::Parse(e)) => Err(e),
+            reply => Ok(reply?),
+        };
+        if let Ok(reply) = &reply {
+            show_thought_process("Planner", &reply.my_thought_process);
+        }
+        self.tracer.planner_decision(
+            "replan",
+            serde_json::json!({
+                "thought_process": reply.as_ref().map(|r| &r.my_thought_process).ok(),
+                "steps": reply.as_ref().map(|r| &r.plan.steps).ok(),
+                "error": reply.as_ref().err(),
+            }),
+        );
+        match reply {
+            Ok(reply) => Ok(Some(reply.plan)),
+            Err(e) => {
+                println!(
+                    "Could not read the revised plan ({}); keeping the remaining steps",
+                    e
+                );
+                Ok(None)
+            }
         }
-        Ok(res)
     }
 
     pub async fn _is_termination(
         &self,
-        chat_request: &mut ChatCompletionRequest,
         current_text_result: &str,
         instruction: &str,
-    ) -> (bool, String) {
+    ) -> AgentResult<(bool, String)> {
         let user_prompt = format!(
             "Given the task: {:?}, examine current result: {}, please decide whether the task is done or not",
             instruction,
             current_text_result
         );
 
-        println!("{:?}", user_prompt.clone());
-
-        let raw_reply = chat_completions_full(chat_request, &IS_TERMINATION_PROMPT, &user_prompt)
+        let reply = match self
+            .structured_completion::<TerminationReply>(
+                AgentRole::Gatekeeper,
+                &self.prompt("termination"),
+                &user_prompt,
+            )
             .await
-            .expect("llm generation failure");
-
-        println!(
-            "_is_termination raw_reply: {:?}",
-            raw_reply.content_to_string()
+        {
+            Err(AgentError::Parse(e)) => {
+                println!("[WARN] Could not read the gatekeeper's reply: {}", e);
+                TerminationReply::default()
+            }
+            reply => reply?,
+        };
+        self.tracer.planner_decision(
+            "goal_check",
+            serde_json::json!({ "goal_met": reply.terminate, "key_points": reply.key_points }),
         );
 
-        let (terminate_or_not, _, key_points) =
-            parse_next_move_and_(&raw_reply.content_to_string(), None);
-
-        (terminate_or_not, key_points.join(","))
+        Ok((reply.terminate, reply.key_points.join(",")))
     }
 
-    pub async fn code_with_python(
-        &self,
-        chat_request: &mut ChatCompletionRequest,
-        message_text: &str,
-    ) -> anyhow::Result<()> {
-        let formatter = ITERATE_CODING_START_TEMPLATE.lock().unwrap();
-        let user_prompt = formatter(&[message_text]);
+    /// Generates Python for `message_text`, runs it and lets the gatekeeper judge the output,
+    /// feeding each result back to the coder until the gatekeeper says TERMINATE.
+    pub async fn code_with_python(&self, message_text: &str) -> AgentResult<CodingOutcome> {
+        let mut user_prompt = self
+            .templates
+            .render("coding_start", &[("task", message_text)]);
+        // a fresh coder thread per task, so concurrent coding tools never interleave
+        let mut conversation = Conversation::for_role(AgentRole::Coder, &self.prompt("coder"));
+        let mut last_good_run: Option<CodingOutcome> = None;
+        let mut last_error: Option<String> = None;
 
-        for n in 1..9 {
+        for n in 1..=self.max_coding_iterations {
             println!("Iteration: {}", n);
-            match chat_completions_full(chat_request, &CODE_PYTHON_PROMPT, &user_prompt)
+            if let Some(summary) = self
+                .compress_if_needed(&mut conversation, &user_prompt)
                 .await?
-                .content
             {
-                NousContent::Text(_out) => {
-                    // let head: String = _out.chars().take(200).collect::<String>();
-                    println!("Raw generation {n}:\n {}\n\n", _out.clone());
-                    let (this_round_good, code, exec_result) = run_python_wrapper(&_out).await;
-                    println!("code:\n{}\n\n", code.clone());
-                    println!("Run result {n}: {}\n", exec_result.clone());
-
-                    if this_round_good {
-                        let (terminate_or_not, key_points) = self
-                            ._is_termination(chat_request, &exec_result, &user_prompt)
-                            .await;
-                        println!("Termination Check: {}\n", terminate_or_not);
-                        // if terminate_or_not {
-                        //     println!("key_points: {:?}\n", key_points);
-
-                        //     self.get_user_feedback().await;
-                        // }
-                    }
-
-                    let formatter = if this_round_good {
-                        ITERATE_CODING_INCORRECT_TEMPLATE.lock().unwrap()
-                    } else {
-                        ITERATE_CODING_FAIL_TEMPLATE.lock().unwrap()
-                    };
+                let reminder = self.templates.render(
+                    "coding_history",
+                    &[("task", message_text), ("summary", &summary)],
+                );
+                user_prompt = format!("{}\n\n{}", reminder, user_prompt);
+            }
 
-                    let user_prompt = formatter(&[&code, &exec_result]);
-                    // let result_message = Message {
-                    //     name: None,
-                    //     content: NousContent::Text(user_prompt),
-                    //     role: ChatCompletionRole::User,
-                    // };
+            let reply = self
+                .complete_partial(AgentRole::Coder.label(), &mut conversation, &user_prompt)
+                .await?
+                .content_to_string();
+            self.record_llm_call(AgentRole::Coder, &user_prompt, &reply);
 
-                    // messages.push(result_message);
+            let mut code = extract_code(&reply);
+            if code.is_empty() {
+                user_prompt = self
+                    .templates
+                    .render("coding_retry", &[("error", &self.prompt("coding_invalid"))]);
+                continue;
+            }
+            match self.review(Checkpoint::BeforePython, &code).await? {
+                Feedback::Approve => {}
+                Feedback::Edit(edited) => code = edited,
+                Feedback::Reject(comment) => {
+                    user_prompt = self
+                        .templates
+                        .render("coding_rejected", &[("code", &code), ("comment", &comment)]);
+                    continue;
                 }
-                _ => unreachable!(),
+                Feedback::Abort => return Err(AgentError::Aborted),
             }
-        }
-        Ok(())
-    }
-}
 
-/* pub async fn compress_chat_history(message_history: &Vec<Message>) -> Vec<Message> {
-    let message_history = message_history.clone();
-    let (system_messages, messages) = message_history.split_at(2);
-    let mut system_messages = system_messages.to_vec();
+            let start = Instant::now();
+            let run = self.run_python(&code)?;
+            self.tracer.record(TraceEvent::PythonExecution {
+                code: code.clone(),
+                stdout: run.clone().ok(),
+                error: run.clone().err(),
+                duration_ms: elapsed_ms(start),
+            });
+            let (this_round_good, exec_result) = match run {
+                Ok(output) => (true, output),
+                Err(error) => (false, error),
+            };
 
-    let chat_history_text = messages
-        .into_iter()
-        .map(|m| m.content_to_string())
-        .collect::<Vec<String>>()
-        .join("\n");
-
-    let messages = vec![
-        Message {
-            role: ChatCompletionRole::System,
-            name: None,
-            content: NousContent::Text(FURTER_TASK_BY_TOOLCALL_PROMPT.to_string()),
-        },
-        Message {
-            role: ChatCompletionRole::User,
-            name: None,
-            content: NousContent::Text(chat_history_text),
-        }
-    ];
-
-    let max_token = 1000u16;
-    let output: NousResponseMessage = chat_completions_full(
-        messages.clone(),
-        max_token
-    ).await.expect("Failed to generate reply");
-
-    match output.content {
-        NousContent::Text(compressed) => {
-            let message = Message {
-                role: ChatCompletionRole::User,
-                name: None,
-                content: NousContent::Text(compressed),
+            if !this_round_good {
+                last_error = Some(exec_result.clone());
+                user_prompt = self
+                    .templates
+                    .render("coding_fail", &[("code", &code), ("error", &exec_result)]);
+                continue;
+            }
+
+            let (terminate_or_not, key_points) =
+                self._is_termination(&exec_result, message_text).await?;
+            println!("Termination Check: {}\n", terminate_or_not);
+
+            let outcome = CodingOutcome {
+                code,
+                output: exec_result,
+                key_points,
+                iterations: n,
+                accepted: terminate_or_not,
             };
+            if terminate_or_not {
+                conversation.push_user(&self.templates.render(
+                    "coding_success",
+                    &[("code", &outcome.code), ("result", &outcome.output)],
+                ));
+                self.threads
+                    .lock()
+                    .unwrap()
+                    .insert(AgentRole::Coder, conversation);
+                return Ok(outcome);
+            }
+
+            user_prompt = self.templates.render(
+                "coding_incorrect",
+                &[("code", &outcome.code), ("result", &outcome.output)],
+            );
+            last_good_run = Some(outcome);
+        }
 
-            system_messages.push(message);
+        self.threads
+            .lock()
+            .unwrap()
+            .insert(AgentRole::Coder, conversation);
+        match (last_good_run, last_error) {
+            (Some(outcome), _) => Ok(outcome),
+            (None, Some(error)) => Err(AgentError::Execution(error)),
+            (None, None) => Err(AgentError::LimitExceeded {
+                what: "coding iteration".to_string(),
+                limit: self.max_coding_iterations,
+            }),
         }
-        _ => unreachable!(),
     }
+}
+
+/// The output of the final step, or of every final step when the plan ends in several branches.
+fn final_output(plan: &Plan, outputs: &HashMap<String, String>) -> String {
+    let final_steps = plan.final_steps();
+    if let [step] = final_steps.as_slice() {
+        return outputs.get(&step.id).cloned().unwrap_or_default();
+    }
+    final_steps
+        .iter()
+        .filter_map(|step| Some(format!("[{}] {}", step.id, outputs.get(&step.id)?)))
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
 
-    system_messages
-} */
+/// Prints the reasoning a reply gave alongside its answer.
+fn show_thought_process(who: &str, thoughts: &[String]) {
+    if thoughts.is_empty() {
+        return;
+    }
+    println!("{}'s reasoning:", who);
+    for thought in thoughts {
+        println!("  - {}", thought);
+    }
+}
@@ -0,0 +1,330 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::Value;
+
+const DOUBLE_QUOTES: &[char] = &['"', '\u{201c}', '\u{201d}'];
+const SINGLE_QUOTES: &[char] = &['\'', '\u{2018}', '\u{2019}'];
+
+/// The JSON a model reply carries: the first object found, or failing that the first
+/// array. Fenced ```json blocks are tried before the surrounding prose, and candidates
+/// that don't parse as written are repaired with `repair_json`.
+pub fn extract_json(text: &str) -> Option<Value> {
+    let candidates = fenced_blocks(text)
+        .into_iter()
+        .chain(std::iter::once(text))
+        .collect::<Vec<&str>>();
+    for opener in ['{', '['] {
+        for candidate in &candidates {
+            if let Some(value) = first_parsable(candidate, opener) {
+                return Some(value);
+            }
+        }
+    }
+    None
+}
+
+static FENCE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?s)```[A-Za-z]*[ \t]*\n?(.*?)(?:```|\z)").unwrap());
+
+/// The bodies of ``` fences, including one left open by a cut-off reply.
+fn fenced_blocks(text: &str) -> Vec<&str> {
+    FENCE
+        .captures_iter(text)
+        .filter_map(|cap| cap.get(1))
+        .map(|m| m.as_str())
+        .filter(|body| !body.trim().is_empty())
+        .collect()
+}
+
+/// Every JSON object or array in `text`, in order, each repaired as in `extract_json`.
+/// Values nested in one that parses are not listed on their own.
+pub fn extract_json_values(text: &str) -> Vec<Value> {
+    values_in(text, parse_at)
+}
+
+/// Like `extract_json_values`, but only the values that parse as written, for text where
+/// a repaired value, say one cut off mid-string, would be wrong to act on.
+pub fn complete_json_values(text: &str) -> Vec<Value> {
+    values_in(text, parse_as_written)
+}
+
+fn values_in(text: &str, parse: fn(&str) -> Option<(Value, usize)>) -> Vec<Value> {
+    let mut values = Vec::new();
+    let mut start = 0;
+    while let Some(offset) = text[start..].find(['{', '[']) {
+        let at = start + offset;
+        match parse(&text[at..]) {
+            Some((value, consumed)) => {
+                values.push(value);
+                start = at + consumed;
+            }
+            None => start = at + 1,
+        }
+    }
+    values
+}
+
+fn first_parsable(text: &str, opener: char) -> Option<Value> {
+    text.match_indices(opener)
+        .find_map(|(start, _)| parse_at(&text[start..]).map(|(value, _)| value))
+}
+
+/// The value starting at the beginning of `text`, and the bytes it took up.
+fn parse_at(text: &str) -> Option<(Value, usize)> {
+    let scan = scan(text);
+    serde_json::from_str::<Value>(&text[..scan.consumed])
+        .ok()
+        .or_else(|| serde_json::from_str::<Value>(&scan.repaired).ok())
+        .map(|value| (value, scan.consumed))
+}
+
+fn parse_as_written(text: &str) -> Option<(Value, usize)> {
+    let consumed = scan(text).consumed;
+    serde_json::from_str::<Value>(&text[..consumed])
+        .ok()
+        .map(|value| (value, consumed))
+}
+
+/// Rewrites the JSON-like text at the start of `candidate` into strict JSON: single and
+/// smart quotes become double quotes, trailing commas are dropped, raw newlines in
+/// strings are escaped and whatever a cut-off reply left open is closed.
+pub fn repair_json(candidate: &str) -> String {
+    scan(candidate).repaired
+}
+
+struct Scan {
+    repaired: String,
+    /// Bytes of the input up to where the outermost bracket closed, or all of it.
+    consumed: usize,
+}
+
+/// Walks one JSON value from the start of `text`, tracking strings and brackets, and
+/// stops where the outermost bracket closes.
+fn scan(text: &str) -> Scan {
+    let mut out = String::new();
+    let mut stack: Vec<char> = Vec::new();
+    // the quote that opened the current string, if inside one
+    let mut string: Option<char> = None;
+    let mut last_structural = ' ';
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if let Some(opener) = string {
+            match c {
+                '\\' => {
+                    if let Some((_, escaped)) = chars.next() {
+                        match escaped {
+                            '\'' => out.push('\''),
+                            _ => {
+                                out.push('\\');
+                                out.push(escaped);
+                            }
+                        }
+                    }
+                }
+                _ if closes(opener, c, &text[i + c.len_utf8()..]) => {
+                    out.push('"');
+                    string = None;
+                }
+                '"' => out.push_str("\\\""),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                _ => out.push(c),
+            }
+            continue;
+        }
+
+        match c {
+            _ if DOUBLE_QUOTES.contains(&c) || SINGLE_QUOTES.contains(&c) => {
+                out.push('"');
+                string = Some(c);
+            }
+            '{' | '[' => {
+                stack.push(c);
+                out.push(c);
+                last_structural = c;
+            }
+            '}' | ']' => {
+                stack.pop();
+                out.push(c);
+                last_structural = c;
+                if stack.is_empty() {
+                    return Scan {
+                        repaired: out,
+                        consumed: i + 1,
+                    };
+                }
+            }
+            ',' => {
+                let rest = text[i + 1..].trim_start();
+                if !(rest.starts_with('}') || rest.starts_with(']')) {
+                    out.push(c);
+                    last_structural = c;
+                }
+            }
+            ':' => {
+                out.push(c);
+                last_structural = c;
+            }
+            _ => out.push(c),
+        }
+        if stack.is_empty() {
+            // a lone scalar; only the bracketed forms are extracted
+            break;
+        }
+    }
+
+    // the reply was cut off: finish the open string and value, then close the brackets
+    if string.is_some() {
+        out.push('"');
+    }
+    let trimmed = out.trim_end().trim_end_matches(',').to_string();
+    out = trimmed;
+    if out.ends_with(':') {
+        out.push_str(" null");
+    } else if stack.last() == Some(&'{')
+        && matches!(last_structural, '{' | ',')
+        && !out.ends_with(['{', ','])
+    {
+        out.push_str(": null");
+    }
+    for open in stack.iter().rev() {
+        out.push(if *open == '{' { '}' } else { ']' });
+    }
+    Scan {
+        repaired: out,
+        consumed: text.len(),
+    }
+}
+
+/// Whether `c` ends a string opened with `opener`. A plain `"` string ends only at a
+/// plain `"`, as in JSON; other quotes end one only when followed by a delimiter, so
+/// apostrophes such as "today's" stay inside it.
+fn closes(opener: char, c: char, rest: &str) -> bool {
+    if opener == '"' {
+        return c == '"';
+    }
+    let family = if DOUBLE_QUOTES.contains(&opener) {
+        DOUBLE_QUOTES
+    } else {
+        SINGLE_QUOTES
+    };
+    family.contains(&c)
+        && rest
+            .trim_start()
+            .chars()
+            .next()
+            .is_none_or(|next| matches!(next, ',' | ':' | '}' | ']'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn extracts_json_from_model_replies() {
+        let cases: &[(&str, &str, Value)] = &[
+            ("json fence", "```json\n{\"a\": 1}\n```", json!({"a": 1})),
+            (
+                "fence without a language",
+                "Here:\n```\n{\"a\": 1}\n```\nDone.",
+                json!({"a": 1}),
+            ),
+            (
+                "prose around the JSON",
+                "Sure! My plan is {\"a\": [1, 2]} and that's it.",
+                json!({"a": [1, 2]}),
+            ),
+            (
+                "brackets inside strings",
+                r#"{"a": "x}y]z", "b": "{[not json"}"#,
+                json!({"a": "x}y]z", "b": "{[not json"}),
+            ),
+            (
+                "key_points with commas",
+                r#"{"key_points": ["Paris, France", "1,000 km"]}"#,
+                json!({"key_points": ["Paris, France", "1,000 km"]}),
+            ),
+            (
+                "trailing commas",
+                "{\"a\": [1, 2,], \"b\": 3,}",
+                json!({"a": [1, 2], "b": 3}),
+            ),
+            (
+                "single quotes",
+                "{'a': 'b', 'c': ['d']}",
+                json!({"a": "b", "c": ["d"]}),
+            ),
+            (
+                "smart quotes",
+                "{\u{201c}a\u{201d}: \u{201c}b\u{201d}, \u{2018}c\u{2019}: \u{2018}d\u{2019}}",
+                json!({"a": "b", "c": "d"}),
+            ),
+            (
+                "apostrophes inside single-quoted strings",
+                "{'task': 'find today's weather', 'n': 'it's'}",
+                json!({"task": "find today's weather", "n": "it's"}),
+            ),
+            (
+                "apostrophes inside double-quoted strings",
+                r#"{"task": "it's Bob's"}"#,
+                json!({"task": "it's Bob's"}),
+            ),
+            (
+                "raw newline inside a string",
+                "{\"a\": \"line one\nline two\"}",
+                json!({"a": "line one\nline two"}),
+            ),
+            (
+                "cut off inside a string",
+                "```json\n{\"steps_to_take\": [\"search the web\", \"summar",
+                json!({"steps_to_take": ["search the web", "summar"]}),
+            ),
+            (
+                "cut off after a colon",
+                "{\"a\": 1, \"b\":",
+                json!({"a": 1, "b": null}),
+            ),
+            (
+                "cut off after a key",
+                "{\"a\": 1, \"b\"",
+                json!({"a": 1, "b": null}),
+            ),
+            (
+                "cut off after a comma",
+                "{\"a\": [1, 2,",
+                json!({"a": [1, 2]}),
+            ),
+            (
+                "objects before arrays",
+                "[1, 2] then {\"a\": 1}",
+                json!({"a": 1}),
+            ),
+            ("bare array", "Steps: [\"a\", \"b\"]", json!(["a", "b"])),
+        ];
+        for (what, text, expected) in cases {
+            assert_eq!(extract_json(text).as_ref(), Some(expected), "{}", what);
+        }
+    }
+
+    #[test]
+    fn no_json_in_plain_text() {
+        assert_eq!(extract_json("The answer is 42."), None);
+        assert_eq!(extract_json(""), None);
+    }
+
+    #[test]
+    fn lists_every_value_in_order() {
+        let values = extract_json_values("first {\"a\": {\"b\": 1}} then [2, 3] and {\"c\": 4}");
+        assert_eq!(
+            values,
+            vec![json!({"a": {"b": 1}}), json!([2, 3]), json!({"c": 4})]
+        );
+        assert_eq!(
+            complete_json_values("{\"a\": 1} then {\"b\": [2, \"cut"),
+            vec![json!({"a": 1})]
+        );
+    }
+}
@@ -1,11 +1,25 @@
+pub mod cassette;
+pub mod chat_backend;
+pub mod config;
+pub mod conversation;
+pub mod error;
+pub mod eval;
 pub mod exec_python;
+pub mod feedback;
+pub mod group_chat;
 pub mod immutable_agent;
+pub mod json_extract;
 pub mod nous_structs;
+pub mod planning;
+pub mod run_state;
+pub mod schema;
+pub mod templates;
+pub mod tool_prompt;
+pub mod tools;
+pub mod trace;
+pub mod usage;
 pub mod utils;
 pub mod webscraper_hook;
-use std::sync::{Arc, Mutex};
-
-type FormatterFn = Box<dyn (Fn(&[&str]) -> String) + Send + Sync>;
 use chrono::Utc;
 use lazy_static::lazy_static;
 use once_cell::sync::Lazy;
@@ -65,143 +79,13 @@ Use this approach to ensure that the user receives precise, direct, and executab
     // Reply "TERMINATE" in the end when everything is done.
 
     pub static ref FURTER_TASK_BY_TOOLCALL_PROMPT: String =
-        r#"<|im_start|>system You are a function calling AI model. You are provided with function signatures within <tools></tools> XML tags. You may call one or more functions to assist with the user query. Don't make assumptions about what values to plug into functions. Here are the available tools: <tools>
-    
-    The function "code_with_python" generates clean, executable Python code for various tasks based on the user input. For example, calling "code_with_python("key_points": "Create a Python script that reads a CSV file and plots a graph")" will generate Python code that performs this task.
-    
-    The function "get_webpage_text" retrieves all text content from a given URL, which can be useful for extracting information from web pages or articles. For example, calling "get_webpage_text("https://example.com")" will fetch the text from Example.com.
-    
-    The function "search_with_bing" performs an internet search using Bing and returns relevant results based on the query provided by the user. This can be useful for finding up-to-date information on various topics. For example, "search_with_bing("latest AI research trends")" will return search results related to the latest trends in AI research.
-    
-    {
-        "name": "get_webpage_text",
-        "description": "Retrieves all text content from a specified website URL.",
-        "parameters": {
-            "url": {
-                "type": "string",
-                "description": "The URL of the website from which to fetch the text content"
-            }
-        },
-        "required": ["url"],
-        "type": "object"
-    }
-    
-    {
-        "name": "code_with_python",
-            "description": "Generates clean, executable Python code for various tasks",
-            "parameters": {
-                "key_points": {
-                    "type": "string",
-                    "description": "Key points from input that describes what kind of problem needs to be solved with Python code."
-                }
-            },
-            "required": ["key_points"],
-            "type": "object"
- }
- 
- {
-    "name": "search_with_bing",
-    "description": "Conducts an internet search using Bing search engine and returns relevant results.",
-    "parameters": { 
-         "query": { 
-             "type": "string", 
-             "description": "The search query to be executed on Bing" 
-          } 
-     }, 
-     "required": ["query"], 
-     "type": "object"
- }
-
-Examples of toolcalls for different scenarios and tools:
-1. To retrieve webpage text:
-<tool_call>
-{"arguments":{"url":"https://example.com"}, 
-"name":"get_webpage_text"}
-</tool_call>
-
-2. To generate Python code:
-<tool_call>
-{"arguments":{"key_points":"Create a Python script that reads data from an API and stores it in a database"}, 
-"name":"code_with_python"}
-</tool_call>
-
-3. To perform an internet search:
-<tool_call>
-{"arguments":{"query":"best practices in software development"}, 
-"name":"search_with_bing"}
-</tool_call>
-
-For each function call return a json object with function name and arguments within <tool_call></tool_call> XML tags as follows:
-<tool_call>
-{"arguments": <args-dict>, 
-"name":"<function-name>"}
-</tool_call>"#.to_string();
-
-    pub static ref ITERATE_CODING_START_TEMPLATE: Arc<Mutex<FormatterFn>> = Arc::new(
-        Mutex::new(Box::new(|args: &[&str]| { format!("Here is the task for you: {}", args[0]) }))
-    );
-
-    pub static ref ITERATE_CODING_INVALID_TEMPLATE: String =
-        "Failed to create valid Python code".to_string();
-
-    pub static ref ITERATE_CODING_SUCCESS_TEMPLATE: Arc<Mutex<FormatterFn>> = Arc::new(
-        Mutex::new(
-            Box::new(|args: &[&str]| {
-                format!(
-                    "Successfully executed the code below:\n{}\n producing the following result:\n{}",
-                    args[0],
-                    args[1]
-                )
-            })
-        )
-    );
-
-    pub static ref ITERATE_CODING_INCORRECT_TEMPLATE: Arc<Mutex<FormatterFn>> = Arc::new(
-        Mutex::new(
-            Box::new(|args: &[&str]| {
-                format!(
-                    "Executed the code below:\n{}\n producing the following result, but the result is incorrect:\n{}",
-                    args[0],
-                    args[1]
-                )
-            })
-        )
-    );
-
-    pub static ref ITERATE_CODING_FAIL_TEMPLATE: Arc<Mutex<FormatterFn>> = Arc::new(
-        Mutex::new(
-            Box::new(|args: &[&str]| {
-                format!(
-                    "Failed to execute the code:\n{}, got the following errors:\n{}",
-                    args[0],
-                    args[1]
-                )
-            })
-        )
-    );
-
-    pub static ref ITERATE_CODING_HISTORY_TEMPLATE: Arc<Mutex<FormatterFn>> = Arc::new(
-        Mutex::new(
-            Box::new(|args: &[&str]| {
-                format!(
-                    "Reminder: you are working towards solving the following task: {}. Here is a summary of the code iterations and their results: {}\nNow let's retry: take care not to repeat previous errors! Try to adopt different approaches.",
-                    args[0],
-                    args[1]
-                )
-            })
-        )
-    );
-
-    pub static ref ITERATE_CODE_RETRY_TEMPLATE: Arc<Mutex<FormatterFn>> = Arc::new(
-        Mutex::new(
-            Box::new(|args: &[&str]| {
-                format!(
-                    "Error: {}\nNow let's retry: take care not to repeat previous errors! Try to adopt different approaches.",
-                    args[0]
-                )
-            })
-        )
-    );
+        r#"You are a function calling AI model. You may call one or more functions to assist with the user query. Don't make assumptions about what values to plug into functions.
+
+{{tool_signatures}}
+
+{{tool_examples}}
+
+{{tool_call_format}}"#.to_string();
 }
 
 pub static GROUNDING_CHECK_TEMPLATE: Lazy<String> = Lazy::new(|| {
@@ -270,11 +154,9 @@ const NEXT_STEP_PLANNING_PROMPT: &'static str =
     r#"
     You are a helpful AI assistant with extensive capabilities. Your goal is to help complete tasks and create plausible answers grounded in real-world history of events and physics with minimal steps.
 
-    You have three built-in tools to solve problems:
-    
-    use_intrinsic_knowledge: You can answer many questions and provide a wealth of knowledge from within yourself. This should be your first approach to problem-solving.
-    code_with_python: Generates and executes Python code for various tasks based on user input. It can handle mathematical computations, data analysis, large datasets, complex operations through optimized algorithms, providing precise, deterministic outputs.
-    search_with_bing: Performs an internet search using Bing and returns relevant results based on a query. Use it to get information you don't have or cross-check for real-world grounding.
+    You have the following built-in tools to solve problems:
+
+{{tool_list}}
     
     When given a task, follow these steps:
     
@@ -285,10 +167,12 @@ const NEXT_STEP_PLANNING_PROMPT: &'static str =
     Pass the task to the next agent by using the original input text verbatim as one single step in the "steps_to_take" section.
     If neither intrinsic knowledge nor built-in tools suffice:
     Strategize and outline necessary steps to achieve the final goal.
-    Each step corresponds to a task that can be completed with one of three approaches: intrinsic knowledge, creating Python code, or searching with Bing.
+    Each step corresponds to a task that can be completed with one of the built-in tools above.
     You don't need to do grounding check for well documented, established facts when there is no direct or inferred reference point of date or locality in task.
     When listing steps:
     Think about why you outlined such a step.
+    Give each step a short "id", the "tool" you intend it to use, and in "depends_on" the ids of the earlier steps whose results it needs.
+    Steps that don't need each other's results must not depend on each other, so they can run at the same time.
     When cascading down to coding tasks:
     Constrain them ideally into one coding task.
     Fill out the "steps_to_take" section of your reply template accordingly.
@@ -311,7 +195,9 @@ const NEXT_STEP_PLANNING_PROMPT: &'static str =
             "...",
             "[Check for unnecessary breakdowns especially for 'coding' tasks]: merge into single coding action"
         ],
-        "steps_to_take": ["Define function checking primes; loop through 2-100 calling function; print primes"]
+        "steps_to_take": [
+            {"id": "s1", "description": "Define function checking primes; loop through 2-100 calling function; print primes", "depends_on": [], "tool": "code_with_python"}
+        ]
     }
     
     Example 2:
@@ -327,8 +213,10 @@ const NEXT_STEP_PLANNING_PROMPT: &'static str =
             "use search_with_bing tool finding current year",
             "collate age based on birth year (1961) and current year"
         ],
-        "steps_to_take": ["Use 'search_with_bing' tool finding current year", 
-                          "Calculate Barack Obama's age from birth year (1961)"]
+        "steps_to_take": [
+            {"id": "s1", "description": "Find the current year", "depends_on": [], "tool": "search_with_bing"},
+            {"id": "s2", "description": "Calculate Barack Obama's age from birth year (1961)", "depends_on": ["s1"], "tool": "use_intrinsic_knowledge"}
+        ]
     }
 
     Example 3:
@@ -340,11 +228,32 @@ const NEXT_STEP_PLANNING_PROMPT: &'static str =
         "my_thought_process": [
            "Determine if this task could utilize built-in tools: YES, can use intrinsic knowledge"
          ],
-         "steps_to_take": ["find out when Steve Jobs died"]
+         "steps_to_take": [
+            {"id": "s1", "description": "find out when Steve Jobs died", "depends_on": [], "tool": "use_intrinsic_knowledge"}
+         ]
     }
 
     Example 4:
 
+    When tasked with "compare the current populations of Tokyo and Delhi," reshape your answer as follows:
+
+    {
+        "my_goal": "goal is comparing two up-to-date population figures",
+        "my_thought_process": [
+            "Determine if this task can be done in single step: NO",
+            "Population figures change over time: need real-world grounding for both cities",
+            "The two lookups don't depend on each other: they can run at the same time",
+            "The comparison needs both lookups"
+        ],
+        "steps_to_take": [
+            {"id": "tokyo", "description": "Find the current population of Tokyo", "depends_on": [], "tool": "search_with_bing"},
+            {"id": "delhi", "description": "Find the current population of Delhi", "depends_on": [], "tool": "search_with_bing"},
+            {"id": "compare", "description": "Compare the two populations", "depends_on": ["tokyo", "delhi"], "tool": "use_intrinsic_knowledge"}
+        ]
+    }
+
+    Example 5:
+
 When tasked with "how to describe Confucius" reshape your answer as follows:
 
 {
@@ -355,7 +264,9 @@ When tasked with "how to describe Confucius" reshape your answer as follows:
        "Confucius was a historical figure whose details are well-documented: no need to check grounding"
        ]
       ],
-      "steps_to_take": ["how to describe Confucius"]
+      "steps_to_take": [
+        {"id": "s1", "description": "how to describe Confucius", "depends_on": [], "tool": "use_intrinsic_knowledge"}
+      ]
 }
 
 Use this format for your response:
@@ -366,7 +277,10 @@ Use this format for your response:
         "...",
         "though_process_N: : my judgement at this step"
     ],
-    "steps_to_take": ["Step description", "..."] 
+    "steps_to_take": [
+        {"id": "s1", "description": "Step description", "depends_on": [], "tool": "tool_name"},
+        {"id": "s2", "description": "...", "depends_on": ["s1"], "tool": "tool_name"}
+    ]
 }
 ```
 "#;
@@ -374,11 +288,9 @@ Use this format for your response:
 const wording_too_strong_PLANNING: &'static str = r#"
     You are a helpful AI assistant with extensive capabilities. Your goal is to help complete tasks and create plausible answers grounded in real-world history of events and physics with minimal steps.
 
-    You have three built-in tools to solve problems:
-    
-    - use_intrinsic_knowledge: You can answer many questions and provide a wealth of knowledge from within yourself. This should be your first approach to problem-solving.
-    - code_with_python: Generates and executes Python code for various tasks based on user input. It handles mathematical computations, data analysis, large datasets, complex operations through optimized algorithms, providing precise, deterministic outputs.
-    - search_with_bing: Performs an internet search using Bing and returns relevant results based on a query. Use it to get information you don't have or cross-check for real-world grounding.
+    You have the following built-in tools to solve problems:
+
+{{tool_list}}
     
     TASK HANDLING INSTRUCTIONS
     
@@ -394,13 +306,15 @@ const wording_too_strong_PLANNING: &'static str = r#"
     
          b. If neither intrinsic knowledge nor built-in tools suffice:
             - Strategize and outline necessary steps to achieve the final goal.
-            - Each step corresponds to a task that can be completed with one of three approaches: intrinsic knowledge, creating Python code, or searching with Bing.
+            - Each step corresponds to a task that can be completed with one of the built-in tools above.
     
     3. GROUNDING CHECKS:
        - You don't need to do grounding checks for well-documented, established facts when there is no direct or inferred reference point of date or locality in the task.
     
     4. LISTING STEPS:
        - Think about why you outlined such a step.
+       - Give each step a short "id", the "tool" you intend it to use, and in "depends_on" the ids of the earlier steps whose results it needs.
+       - Steps that don't need each other's results must not depend on each other, so they can run at the same time.
        - When cascading down to coding tasks:
          * Constrain them ideally into one coding task.
        
@@ -424,7 +338,9 @@ const wording_too_strong_PLANNING: &'static str = r#"
             "...",
             "[Check for unnecessary breakdowns especially for 'coding' tasks]: merge into single coding action"
         ],
-        "steps_to_take": ["Define function checking primes; loop through 2-100 calling function; print primes"]
+        "steps_to_take": [
+            {"id": "s1", "description": "Define function checking primes; loop through 2-100 calling function; print primes", "depends_on": [], "tool": "code_with_python"}
+        ]
     }
     
     Example 2:
@@ -440,8 +356,10 @@ const wording_too_strong_PLANNING: &'static str = r#"
             "use search_with_bing tool finding current year",
             "collate age based on birth year (1961) and current year"
         ],
-        "steps_to_take": ["Use 'search_with_bing' tool finding current year", 
-                          "Calculate Barack Obama's age from birth year (1961)"]
+        "steps_to_take": [
+            {"id": "s1", "description": "Find the current year", "depends_on": [], "tool": "search_with_bing"},
+            {"id": "s2", "description": "Calculate Barack Obama's age from birth year (1961)", "depends_on": ["s1"], "tool": "use_intrinsic_knowledge"}
+        ]
     }
 
     Example 3:
@@ -453,11 +371,32 @@ const wording_too_strong_PLANNING: &'static str = r#"
         ”my_gut_answer”: ”Steve Jobs was world famous; I probably have his death date in my database.”,        "my_thought_process": [
            "Determine if this task could utilize built-in tools: YES, can use intrinsic knowledge"
          ],
-         "steps_to_take": ["find out when Steve Jobs died"]
+         "steps_to_take": [
+            {"id": "s1", "description": "find out when Steve Jobs died", "depends_on": [], "tool": "use_intrinsic_knowledge"}
+         ]
     }
 
     Example 4:
 
+    When tasked with "compare the current populations of Tokyo and Delhi," reshape your answer as follows:
+
+    {
+        "my_goal": "goal is comparing two up-to-date population figures",
+        "my_thought_process": [
+            "Determine if this task can be done in single step: NO",
+            "Population figures change over time: need real-world grounding for both cities",
+            "The two lookups don't depend on each other: they can run at the same time",
+            "The comparison needs both lookups"
+        ],
+        "steps_to_take": [
+            {"id": "tokyo", "description": "Find the current population of Tokyo", "depends_on": [], "tool": "search_with_bing"},
+            {"id": "delhi", "description": "Find the current population of Delhi", "depends_on": [], "tool": "search_with_bing"},
+            {"id": "compare", "description": "Compare the two populations", "depends_on": ["tokyo", "delhi"], "tool": "use_intrinsic_knowledge"}
+        ]
+    }
+
+    Example 5:
+
 When tasked with "how to describe Confucius" reshape your answer as follows:
 
 {
@@ -469,7 +408,9 @@ When tasked with "how to describe Confucius" reshape your answer as follows:
        "Confucius was a historical figure whose details are well-documented: no need to check grounding"
        ]
       ],
-      "steps_to_take": ["how to describe Confucius"]
+      "steps_to_take": [
+        {"id": "s1", "description": "how to describe Confucius", "depends_on": [], "tool": "use_intrinsic_knowledge"}
+      ]
 }
 
 Use this format for your response:
@@ -480,99 +421,132 @@ Use this format for your response:
         "...",
         "though_process_N: : my judgement at this step"
     ],
-    "steps_to_take": ["Step description", "..."] 
+    "steps_to_take": [
+        {"id": "s1", "description": "Step description", "depends_on": [], "tool": "tool_name"},
+        {"id": "s2", "description": "...", "depends_on": ["s1"], "tool": "tool_name"}
+    ]
 }
 ```
 "#;
 
 const NEXT_STEP_BY_TOOLCALL_PROMPT: &'static str = r#"
-<|im_start|>system You are a function-calling AI model. You are provided with function signatures within <tools></tools> XML tags. You may call one or more functions to assist with the user query. Do not make assumptions about what values to plug into functions.
-
-Here are the available tools:
-
-<tools>
-1. **use_intrinsic_knowledge**: 
-Description: Solves tasks using capabilities and knowledge obtained at trainning time, the carveate is that it is frozen by the cut-off date and it's not aware of real world date of its operation.
-Example Call:
-<tool_call>
-{"arguments": {"task": "tell a joke"}, 
-"name": "use_intrinsic_knowledge"}
-</tool_call>
-
-2. **search_with_bing**: 
-Description: Conducts an internet search using Bing search engine and returns relevant results based on the query provided by the user. It's a safe choice to try searching for results; if they are not satisfactory, you can use suspect URLs from these search results with "get_webpage_text" function.
-
-Special Note 1: This function performs an internet search to find relevant webpages based on your query. It helps narrow down potential sources of information before extracting specific content.
-
-Special Note 2: Using search_with_bing as an initial step can make subsequent tasks more targeted by providing exact links that can then be scraped using get_webpage_text. This approach ensures higher relevance and accuracy of retrieved data.
-Example Call:
-<tool_call>
-{"arguments": {"query": "latest AI research trends"}, 
-"name": "search_with_bing"}
-</tool_call>
-
-3. **code_with_python**: 
-Description: Generates clean, executable Python code for various tasks based on user input.
-Example Call:
-<tool_call>
-{"arguments": {"key_points": "Create a Python script that reads a CSV file and plots a graph"}, 
-"name": "code_with_python"}
-</tool_call>
-
-4. **get_webpage_text**: 
-Description: Fetches all textual content from the specified webpage URL and returns it as plain text. It does not parse or structure the data in any specific format, so it may include extraneous information such as navigation menus, advertisements, and other non-essential text elements present on the page.
-
-Special Note 1: This function retrieves raw text from a given URL without filtering out irrelevant content. Therefore, using a URL that is not unique to your solution may result in obtaining unrelated data.
-
-Special Note 2: While this function can extract text from a known relevant webpage directly, it is often more effective to first use search_with_bing to find precise URLs before scraping them for targeted information.
-
-Example Call:
-<tool_call>
-{"arguments": {"url": "https://example.com"}, 
-"name": "get_webpage_text"}
-</tool_call>
-</tools>
-
-Function Definitions:
-
-use_intrinsic_knowledge
-Description: Solves tasks using built-in capabilities.
-Parameters:
-problem: The task you receive (type:string)
-Required Parameters:["task"]
-
-search_with_bing
-Description: Conducts an internet search using Bing search engine and returns relevant results based on the query provided by the user.
-Parameters:
-query: The search query to be executed on Bing (type:string)
-Required Parameters:["query"]
-
-code_with_python
-Description Generates clean executable Python code for various tasks.Parameters key_points Key points describing what kind of problem needs to be solved with Python code(type:string) Required Parameters:["key_points"]
-
-get_webpage_text
-Description Retrieves all textual content froma specified website URL.It does not parse or structure data in any specific format; hence,it may include extraneous information such as navigation menus advertisements,and other non-essential text elements present onthe page.Parameters url The URLofthe website from which to fetch textual content(type:string) Required Parameters:["url"]
-
-Remember that you area dispatcher;you DO NOT workon tasks yourself.
-
-Examples of tool calls for different scenarios:
-
-To handlea task like "tell a joke" with intrinsic knowledge: <tool_call>{"
-arguments": {"task": "tell ajoke"},
-"name": "use_intrinsic_knowledge"}</tool_call>
-To retrieve webpage text: <tool_call>{"
-arguments": {"url": "https://example.com"},
-"name": "get_webpage_text"}</tool_call>
-To generate Python code: <tool_call>{"arguments": {"key_points": "CreateaPython script that reads data from an API and stores it in adatabase"},
-"name": "code_with_python"}</tool_call>
-To perform an internet search: <tool_call>{"
-arguments": {"query": "best practices in software development"},
-"name": "search_with_bing"}</tool_call>
-
-For each function call, return a JSON object with function name and arguments within <tool_call></tools> XML tags as follows:
-
-<tools>  
-{"arguments": <args-dict>,   
-"name": "<function_name>"}
-</tools>
+You are a function-calling AI model. You may call one or more functions to assist with the user query. Do not make assumptions about what values to plug into functions.
+
+{{tool_signatures}}
+
+Remember that you are a dispatcher; you DO NOT work on tasks yourself.
+
+{{tool_examples}}
+
+{{tool_call_format}}
 "#;
+
+const REPLANNING_PROMPT: &str = r#"
+You are a helpful AI assistant revising a plan while it is being carried out. You have the following built-in tools to solve problems:
+
+{{tool_list}}
+
+You will be given the goal, the steps done so far with their results, and the steps still planned. Judge the results against the goal, then decide what remains to be done:
+- keep the remaining steps as they are if they still make sense;
+- revise a step whose description or tool no longer fits what the results showed;
+- insert new steps when the results revealed missing work;
+- drop steps that are no longer needed.
+
+Remaining steps may depend on steps already done, using their ids. Never reuse the id of a step already done for a new step. If nothing is left to do, reply with an empty "steps_to_take" list.
+
+Use this format for your response:
+```json
+{
+    "my_thought_process": [
+        "thought_process_one: my judgement at this step",
+        "...",
+        "thought_process_N: my judgement at this step"
+    ],
+    "steps_to_take": [
+        {"id": "s3", "description": "Step description", "depends_on": ["s1"], "tool": "tool_name"}
+    ]
+}
+```
+"#;
+
+const REACT_PROMPT: &str = r#"
+You are a helpful AI assistant that solves tasks by reasoning and acting in turns. You may call functions to gather information or compute results.
+
+{{tool_signatures}}
+
+In every turn, first write one line starting with "Thought:" about what you know so far and what to do next. Then either:
+- call exactly one function, after which you will receive its result as "Observation: ...", or
+- when you know the answer, write a line starting with "Final Answer:" followed by the complete answer to the task.
+
+Never make up an observation yourself.
+
+{{tool_examples}}
+
+{{tool_call_format}}
+"#;
+
+const GROUP_CHAT_MEMBER_PROMPT: &str = r#"
+You are {{name}}, taking part in a group chat with other AI agents working on the user's task together. The participants are:
+
+{{roster}}
+
+You will be given the conversation so far. Write only your own next message, doing the part your role above describes; don't speak for the others. When the task is fully done and the final answer has been given, end your message with the word {{termination_keyword}}.
+"#;
+
+const GROUP_CHAT_SELECT_SPEAKER_PROMPT: &str = r#"
+You are coordinating a group chat of AI agents working on the user's task together. The participants are:
+
+{{roster}}
+
+You will be given the conversation so far. Decide who should speak next to move the task forward. Reply with the name of that participant only, nothing else.
+"#;
+
+const PLANNER_AGENT_PROMPT: &str = "You are the planner. Break the user's task into short, concrete steps and say who in the group should take each one. Revise the plan when results come in.";
+
+const CODER_AGENT_PROMPT: &str = "You are the coder. When a step needs computation or data processing, solve it with Python using the code_with_python tool and report the result.";
+
+const RESEARCHER_AGENT_PROMPT: &str = "You are the researcher. Look up facts that need real-world grounding with search_with_bing and get_webpage_text, and report what you found with its source.";
+
+const CRITIC_AGENT_PROMPT: &str = "You are the critic. Check the latest results against the user's task, point out mistakes or gaps, and when everything checks out, state the final answer.";
+
+const CODING_START_TEMPLATE: &str = "Here is the task for you: {{task}}";
+
+const CODING_INVALID_TEMPLATE: &str = "Failed to create valid Python code";
+
+const CODING_SUCCESS_TEMPLATE: &str =
+    "Successfully executed the code below:\n{{code}}\n producing the following result:\n{{result}}";
+
+const CODING_INCORRECT_TEMPLATE: &str =
+    "Executed the code below:\n{{code}}\n producing the following result, but the result is incorrect:\n{{result}}";
+
+const CODING_FAIL_TEMPLATE: &str =
+    "Failed to execute the code:\n{{code}}, got the following errors:\n{{error}}";
+
+const CODING_HISTORY_TEMPLATE: &str = "Reminder: you are working towards solving the following task: {{task}}. Here is a summary of the code iterations and their results: {{summary}}\nNow let's retry: take care not to repeat previous errors! Try to adopt different approaches.";
+
+const CODING_REJECTED_TEMPLATE: &str = "The user reviewed the code below and did not let it run:\n{{code}}\nTheir comment: {{comment}}\nPlease revise the code accordingly.";
+
+const CODING_RETRY_TEMPLATE: &str = "Error: {{error}}\nNow let's retry: take care not to repeat previous errors! Try to adopt different approaches.";
+
+const EVAL_JUDGE_PROMPT: &str = r#"
+You are grading an AI agent's answer to a task against a reference answer.
+
+Task: {{task}}
+
+Reference answer: {{expected}}
+
+You will be given the agent's answer. It is correct if it states the same facts or result as the reference answer; wording, formatting and extra explanation don't matter, but any contradiction or missing key result does. Reply with CORRECT or INCORRECT on the first line, followed by one sentence explaining why.
+"#;
+
+const STRUCTURED_RETRY_TEMPLATE: &str = r#"Your reply could not be used: {{error}}.
+Reply again with only a JSON object that follows this schema, keeping the content of your previous answer where it was right:
+```json
+{{schema}}
+```"#;
+
+const TOOL_RETRY_TEMPLATE: &str = r#"{{task}}
+
+You answered this task with the tool call below, but it could not be run:
+{{calls}}
+Error: {{error}}
+Call a tool again with corrected arguments, or reply in plain text if no tool fits."#;
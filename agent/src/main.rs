@@ -1,15 +1,28 @@
 use chat_prompts::PromptTemplateType;
-use clap::Parser;
-use endpoints::chat::{
-    ChatCompletionRequestBuilder, ChatCompletionRequestMessage, ChatCompletionRequestSampling,
-};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use endpoints::chat::{ChatCompletionRequestBuilder, ChatCompletionRequestSampling};
+use llama_agent::cassette::Cassette;
+use llama_agent::chat_backend::*;
+use llama_agent::config::*;
+use llama_agent::eval::*;
+use llama_agent::feedback::*;
+use llama_agent::group_chat::*;
 use llama_agent::immutable_agent::*;
+use llama_agent::run_state::RunStore;
+use llama_agent::tool_prompt::ToolSpecSyntax;
+use llama_agent::trace::Tracer;
+use llama_agent::usage::Budget;
+#[cfg(feature = "wasi-nn")]
 use llama_core::{init_core_context, MetadataBuilder};
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 
 #[derive(Debug, Parser)]
 #[command(author, about, version, long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Model name
     #[arg(short, long, default_value = "default")]
     model_name: String,
@@ -17,10 +30,10 @@ struct Cli {
     #[arg(short = 'a', long, default_value = "default")]
     model_alias: String,
     /// Size of the prompt context
-    #[arg(short, long, default_value = "512")]
+    #[arg(short, long, default_value = "8192")]
     ctx_size: u64,
     /// Number of tokens to predict
-    #[arg(short, long, default_value = "1024")]
+    #[arg(short, long, default_value = "999")]
     n_predict: u64,
     /// Number of layers to run on the GPU
     #[arg(short = 'g', long, default_value = "100")]
@@ -43,9 +56,9 @@ struct Cli {
     /// Repeat alpha frequency penalty. 0.0 = disabled
     #[arg(long, default_value = "0.0")]
     frequency_penalty: f64,
-    /// Sets the prompt template.
-    #[arg(short, long, value_parser = clap::value_parser!(PromptTemplateType), required = true)]
-    prompt_template: PromptTemplateType,
+    /// Sets the prompt template. Required unless the config file sets `model.prompt_template`
+    #[arg(short, long, value_parser = clap::value_parser!(PromptTemplateType))]
+    prompt_template: Option<PromptTemplateType>,
     /// Halt generation at PROMPT, return control.
     #[arg(short, long)]
     reverse_prompt: Option<String>,
@@ -64,20 +77,84 @@ struct Cli {
     /// enable streaming stdout
     #[arg(long, default_value = "false")]
     disable_stream: bool,
+    /// Base URL of an OpenAI-compatible server, e.g. http://localhost:8080/v1. When set, no local model is loaded
+    #[arg(long)]
+    api_base: Option<String>,
+    /// API key sent as a bearer token to the OpenAI-compatible server
+    #[arg(long, requires = "api_base")]
+    api_key: Option<String>,
+    /// Don't ask the OpenAI-compatible server for JSON-only replies, for servers that reject `response_format`
+    #[arg(long, requires = "api_base")]
+    no_json_mode: bool,
+    /// Re-judge the plan after each round of steps, revising it at most this many times
+    #[arg(long)]
+    max_replans: Option<usize>,
+    /// How to work on each input: "plan" to plan then execute, "react" to think and act in turns
+    #[arg(long, default_value = "plan")]
+    mode: RunMode,
+    /// Maximum number of turns in react mode
+    #[arg(long, default_value = "10")]
+    max_react_steps: usize,
+    /// Answer with a planner/researcher/coder/critic group chat, choosing speakers "round-robin" or by "llm"
+    #[arg(long)]
+    group_chat: Option<SpeakerSelection>,
+    /// Checkpoints to stop at for review, comma separated: python, fetch, plan, answer
+    #[arg(long, value_delimiter = ',')]
+    review: Vec<Checkpoint>,
+    /// TOML file defining the model, agents, tools, limits and prompt overrides. Flags given on the command line take precedence over it
+    #[arg(long)]
+    config: Option<String>,
+    /// Directory each run is checkpointed to, so it can be continued with `resume <run-id>`
+    #[arg(long, default_value = "runs")]
+    run_dir: String,
+    /// Append a JSONL trace of every model request, tool call, Python run and planner decision to this file
+    #[arg(long)]
+    trace: Option<String>,
+    /// Stop a run with its partial result once it has spent this many tokens
+    #[arg(long)]
+    token_budget: Option<u64>,
+    /// Stop a run with its partial result once it has made this many model calls
+    #[arg(long)]
+    call_budget: Option<usize>,
+    /// Record every model reply, search, webpage and Python result to this cassette file
+    #[arg(long, conflicts_with = "replay")]
+    record: Option<String>,
+    /// Answer from a recorded cassette file instead of the model, search engine and Python
+    #[arg(long)]
+    replay: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run a JSONL suite of tasks with expected answers and score the agent on it
+    Eval {
+        /// JSONL file with one {"id", "task", "expected", "scoring"} object per line
+        suite: String,
+        /// Config file to evaluate, as NAME=PATH or PATH. Give it more than once to compare setups side by side; defaults to the --config setup
+        #[arg(long = "variant")]
+        variants: Vec<String>,
+        /// Also write the reports to this file as JSON
+        #[arg(long)]
+        report: Option<String>,
+    },
 }
 
 #[allow(unreachable_code)]
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
-    // get the environment variable `PLUGIN_DEBUG`
-    let plugin_debug = std::env::var("PLUGIN_DEBUG").unwrap_or_default();
-    let plugin_debug = match plugin_debug.is_empty() {
-        true => false,
-        false => plugin_debug.to_lowercase().parse::<bool>().unwrap_or(false),
+    // parse the command line arguments, then fill in whatever they leave out from the config file
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches)?;
+    let mut config = match &cli.config {
+        Some(path) => AgentConfig::from_file(path)?,
+        None => AgentConfig::default(),
     };
-
-    // parse the command line arguments
-    let cli = Cli::parse();
+    let overrides = merge_config(&mut cli, &mut config, &matches)?;
+    let prompt_template = cli.prompt_template.ok_or_else(|| {
+        anyhow::anyhow!(
+            "--prompt-template is required unless the config file sets model.prompt_template"
+        )
+    })?;
 
     // log version
     log(format!(
@@ -88,7 +165,7 @@ async fn main() -> anyhow::Result<()> {
     // log the cli options
     log(format!("[INFO] Model name: {}", &cli.model_name));
     log(format!("[INFO] Model alias: {}", &cli.model_alias));
-    log(format!("[INFO] Prompt template: {}", &cli.prompt_template));
+    log(format!("[INFO] Prompt template: {}", &prompt_template));
 
     // reverse prompt
     if let Some(reverse_prompt) = &cli.reverse_prompt {
@@ -99,71 +176,136 @@ async fn main() -> anyhow::Result<()> {
         log(format!("[INFO] system prompt: {}", system_prompt));
     }
 
-    // create a MetadataBuilder instance
-    let builder = MetadataBuilder::new(&cli.model_name, &cli.model_alias, cli.prompt_template)
-        .with_ctx_size(8192)
-        .with_n_predict(999)
-        .with_n_gpu_layers(cli.n_gpu_layers)
-        .with_batch_size(cli.batch_size)
-        .with_repeat_penalty(cli.repeat_penalty)
-        .with_presence_penalty(cli.presence_penalty)
-        .with_frequency_penalty(cli.frequency_penalty)
-        .with_reverse_prompt(cli.reverse_prompt)
-        .enable_prompts_log(cli.log_prompts || cli.log_all)
-        .enable_plugin_log(cli.log_stat || cli.log_all)
-        .enable_debug_log(plugin_debug)
-        .with_temperature(0.1)
-        .with_top_p(1.0);
-    // create a Metadata instance
-    let metadata = builder.build();
-
-    // initialize the core context
-    init_core_context(Some(&[metadata]), None)?;
+    let cassette = match (&cli.record, &cli.replay) {
+        (Some(path), _) => {
+            log(format!("[INFO] Recording to: {}", path));
+            Some(Arc::new(Cassette::record(path)?))
+        }
+        (_, Some(path)) => {
+            log(format!("[INFO] Replaying: {}", path));
+            Some(Arc::new(Cassette::replay(path)?))
+        }
+        _ => None,
+    };
 
-    // get the plugin version info
-    let plugin_info = llama_core::get_plugin_info()?;
-    log(format!(
-        "[INFO] Wasi-nn-ggml plugin: b{build_number} (commit {commit_id})",
-        build_number = plugin_info.build_number,
-        commit_id = plugin_info.commit_id
-    ));
+    let backend: Arc<dyn ChatBackend> = match &cli.api_base {
+        // never called: every reply comes from the cassette
+        _ if cli.replay.is_some() => Arc::new(NoBackend),
+        Some(api_base) => {
+            log(format!("[INFO] Chat backend: {}", api_base));
+            let mut backend = OpenAiHttpBackend::new(api_base);
+            if let Some(api_key) = &cli.api_key {
+                backend = backend.with_api_key(api_key);
+            }
+            Arc::new(backend.with_json_mode(!cli.no_json_mode))
+        }
+        #[cfg(feature = "wasi-nn")]
+        None => {
+            init_llama_core(&cli, prompt_template)?;
+            Arc::new(LlamaCoreBackend)
+        }
+        #[cfg(not(feature = "wasi-nn"))]
+        None => anyhow::bail!("this build has no local model; pass --api-base or --replay"),
+    };
 
-    // create a ChatCompletionRequestSampling instance
-    let sampling = if cli.temp.is_none() && cli.top_p.is_none() {
-        ChatCompletionRequestSampling::Temperature(1.0)
-    } else if let Some(temp) = cli.temp {
-        ChatCompletionRequestSampling::Temperature(temp)
-    } else if let Some(top_p) = cli.top_p {
-        ChatCompletionRequestSampling::TopP(top_p)
-    } else {
-        let temp = cli.temp.unwrap();
-        ChatCompletionRequestSampling::Temperature(temp)
+    // create a ChatCompletionRequestSampling instance from the flags alone; each config's
+    // sampling is applied on top when its agents are built, with the flags winning again
+    let explicit = &overrides.sampling;
+    let sampling = match (explicit.temperature, explicit.top_p) {
+        (Some(temp), _) => ChatCompletionRequestSampling::Temperature(temp),
+        (None, Some(top_p)) => ChatCompletionRequestSampling::TopP(top_p),
+        (None, None) => ChatCompletionRequestSampling::Temperature(DEFAULT_TEMPERATURE),
     };
 
-    // create a chat request
+    // create a chat request; the agent copies its settings into every call it makes
     let mut chat_request = ChatCompletionRequestBuilder::new(&cli.model_name, vec![])
-        .with_presence_penalty(cli.presence_penalty)
-        .with_frequency_penalty(cli.frequency_penalty)
         .with_sampling(sampling)
         .enable_stream(!cli.disable_stream)
         .build();
-
-    // add system message if provided
-    if let Some(system_prompt) = &cli.system_prompt {
-        let system_message = ChatCompletionRequestMessage::new_system_message(system_prompt, None);
-
-        chat_request.messages.push(system_message);
-    }
+    explicit.apply_to(&mut chat_request);
 
     let readme =
         "
 ================================== Running in interactive mode. ===================================\n
     - Press [Ctrl+C] to interject at any time.
     - Press [Return] to end the input.
-    - For multi-line inputs, end each line with '\\' and press [Return] to get another line.\n";
-    log(readme);
+    - For multi-line inputs, end each line with '\\' and press [Return] to get another line.
+    - Type `resume <run-id>` to continue an interrupted run, or `rerun <run-id> <step-id>`
+      to run a step of it again, along with the steps after it.\n";
+    if cli.command.is_none() {
+        log(readme);
+    }
+
+    let system_prompt = cli
+        .system_prompt
+        .clone()
+        .unwrap_or_else(|| "you're user_proxy".to_string());
+    let tracer = match &cli.trace {
+        Some(path) => {
+            log(format!("[INFO] Trace file: {}", path));
+            Tracer::jsonl(path)?
+        }
+        None => Tracer::default(),
+    };
+    let mut base = ImmutableAgent::new("user_proxy", &system_prompt).with_backend(backend);
+    if let Some(cassette) = cassette {
+        base = base.with_cassette(cassette);
+    }
+    let base = base
+        .with_tracer(tracer)
+        .with_budget(Budget {
+            max_tokens: cli.token_budget,
+            max_calls: cli.call_budget,
+        })
+        .with_request_template(chat_request)
+        .with_context_window(cli.ctx_size, cli.n_predict)
+        .with_tool_syntax(ToolSpecSyntax::from(prompt_template))
+        .with_react_steps(cli.max_react_steps)
+        .with_feedback(Arc::new(TerminalFeedback), &cli.review)
+        .with_run_store(RunStore::new(&cli.run_dir));
+
+    let user_proxy = config.build_agents(&base)?.remove(0);
+    if let Some(Command::Eval {
+        suite,
+        variants,
+        report,
+    }) = &cli.command
+    {
+        let tasks = load_suite(suite)?;
+        let mut reports = Vec::new();
+        if variants.is_empty() {
+            let name = cli.config.as_deref().unwrap_or("default");
+            reports.push(run_suite(&user_proxy, name, &tasks, cli.mode).await);
+        }
+        for variant in variants {
+            let (name, path) = match variant.split_once('=') {
+                Some((name, path)) => (name.to_string(), path),
+                None => (variant.clone(), variant.as_str()),
+            };
+            let mut variant = AgentConfig::from_file(path)?;
+            config
+                .check_variant(&variant)
+                .map_err(|e| anyhow::anyhow!("Variant {}: {}", name, e))?;
+            variant.override_with(&overrides);
+            let agent = variant.build_agents(&base)?.remove(0);
+            reports.push(run_suite(&agent, &name, &tasks, cli.mode).await);
+        }
 
-    let user_proxy = ImmutableAgent::new("user_proxy", "you're user_proxy");
+        for report in &reports {
+            println!("\n{}", report.render());
+        }
+        if reports.len() > 1 {
+            println!("\n{}", compare(&reports));
+        }
+        if let Some(path) = report {
+            std::fs::write(path, serde_json::to_string_pretty(&reports)?)?;
+        }
+        return Ok(());
+    }
+    let team = match (&cli.group_chat, &config.group_chat) {
+        (None, None) => None,
+        (selection, _) => Some(config.build_group_chat(&base, selection.clone())?),
+    };
 
     loop {
         println!("\n[You]: ");
@@ -177,23 +319,159 @@ async fn main() -> anyhow::Result<()> {
 
         // chat_request.messages.push(user_message);
 
-        if cli.log_stat || cli.log_all {
-            print_log_begin_separator("STATISTICS (Set Input)", Some("*"), None);
+        println!("\n[Bot]:");
+        let words = user_input.split_whitespace().collect::<Vec<&str>>();
+        let result = match (words.as_slice(), &team) {
+            (["resume", run_id], _) => user_proxy.resume(run_id).await,
+            (["rerun", run_id, step_id], _) => {
+                println!("New description for step {} (Return to keep it):", step_id);
+                let edited = read_input();
+                let edited = Some(edited.trim()).filter(|e| !e.is_empty());
+                user_proxy.rerun_from(run_id, step_id, edited).await
+            }
+            (_, Some(team)) => {
+                user_proxy.usage.reset();
+                team.run(&user_input)
+                    .await
+                    .map(|outcome| outcome.answer(&team.termination_keyword))
+            }
+            (_, None) => user_proxy.run(&user_input, cli.mode).await,
+        };
+
+        match result {
+            Ok(answer) => println!("{}", answer),
+            Err(e) => {
+                log(format!("[ERROR] {}", e));
+                let run_by_proxy =
+                    team.is_none() || matches!(words.as_slice(), ["resume", _] | ["rerun", _, _]);
+                if let (true, Some(run_id)) = (run_by_proxy, user_proxy.current_run_id()) {
+                    log(format!("[INFO] continue with `resume {}`", run_id));
+                }
+            }
         }
 
+        let usage = user_proxy.usage.report();
         if cli.log_stat || cli.log_all {
+            print_log_begin_separator("STATISTICS", Some("*"), None);
+            println!("{}", usage.details());
             print_log_end_separator(Some("*"), None);
+        } else {
+            log(format!("[INFO] {}", usage.summary()));
         }
+    }
 
-        println!("\n[Bot]:");
-        let task_vec = user_proxy
-            .next_step_planning(&mut chat_request, &user_input)
-            .await;
+    Ok(())
+}
 
-        let o = user_proxy.stepper(&mut chat_request, &task_vec).await;
+const DEFAULT_TEMPERATURE: f64 = 0.1;
+
+/// Fills every flag not given on the command line from `config`, and makes the flags that
+/// were given win over the config's settings. Returns those flags, to apply to other
+/// configs the same way.
+fn merge_config(
+    cli: &mut Cli,
+    config: &mut AgentConfig,
+    matches: &ArgMatches,
+) -> anyhow::Result<Overrides> {
+    let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    fn fill<T: Clone>(explicit: bool, field: &mut T, value: &Option<T>) {
+        if let (false, Some(value)) = (explicit, value) {
+            *field = value.clone();
+        }
+    }
 
-        println!("{:?}", o);
+    let model = &config.model;
+    fill(from_cli("model_name"), &mut cli.model_name, &model.name);
+    fill(from_cli("model_alias"), &mut cli.model_alias, &model.alias);
+    fill(from_cli("ctx_size"), &mut cli.ctx_size, &model.ctx_size);
+    fill(from_cli("n_predict"), &mut cli.n_predict, &model.n_predict);
+    fill(
+        from_cli("n_gpu_layers"),
+        &mut cli.n_gpu_layers,
+        &model.n_gpu_layers,
+    );
+    fill(
+        from_cli("batch_size"),
+        &mut cli.batch_size,
+        &model.batch_size,
+    );
+    if cli.prompt_template.is_none() {
+        cli.prompt_template = config.prompt_template()?;
     }
+    if cli.api_base.is_none() {
+        cli.api_base = model.api_base.clone();
+        cli.api_key = cli.api_key.clone().or(model.api_key.clone());
+    }
+
+    let overrides = Overrides {
+        sampling: SamplingConfig {
+            temperature: cli.temp,
+            top_p: cli.top_p,
+            presence_penalty: from_cli("presence_penalty").then_some(cli.presence_penalty),
+            frequency_penalty: from_cli("frequency_penalty").then_some(cli.frequency_penalty),
+            repeat_penalty: from_cli("repeat_penalty").then_some(cli.repeat_penalty),
+        },
+        react_steps: from_cli("max_react_steps").then_some(cli.max_react_steps),
+        max_replans: cli.max_replans,
+        system_prompt: cli.system_prompt.clone(),
+    };
+    config.override_with(&overrides);
+    // the local model takes its sampling when it is loaded
+    let sampling = &config.sampling;
+    if cli.temp.is_none() && cli.top_p.is_none() {
+        cli.temp = sampling.temperature;
+        cli.top_p = sampling.top_p;
+    }
+    fill(false, &mut cli.presence_penalty, &sampling.presence_penalty);
+    fill(
+        false,
+        &mut cli.frequency_penalty,
+        &sampling.frequency_penalty,
+    );
+    fill(false, &mut cli.repeat_penalty, &sampling.repeat_penalty);
+
+    cli.token_budget = cli.token_budget.or(config.limits.token_budget);
+    cli.call_budget = cli.call_budget.or(config.limits.call_budget);
+    Ok(overrides)
+}
+
+#[cfg(feature = "wasi-nn")]
+fn init_llama_core(cli: &Cli, prompt_template: PromptTemplateType) -> anyhow::Result<()> {
+    // get the environment variable `PLUGIN_DEBUG`
+    let plugin_debug = std::env::var("PLUGIN_DEBUG").unwrap_or_default();
+    let plugin_debug = match plugin_debug.is_empty() {
+        true => false,
+        false => plugin_debug.to_lowercase().parse::<bool>().unwrap_or(false),
+    };
+
+    // create a MetadataBuilder instance
+    let builder = MetadataBuilder::new(&cli.model_name, &cli.model_alias, prompt_template)
+        .with_ctx_size(cli.ctx_size)
+        .with_n_predict(cli.n_predict)
+        .with_n_gpu_layers(cli.n_gpu_layers)
+        .with_batch_size(cli.batch_size)
+        .with_repeat_penalty(cli.repeat_penalty)
+        .with_presence_penalty(cli.presence_penalty)
+        .with_frequency_penalty(cli.frequency_penalty)
+        .with_reverse_prompt(cli.reverse_prompt.clone())
+        .enable_prompts_log(cli.log_prompts || cli.log_all)
+        .enable_plugin_log(cli.log_stat || cli.log_all)
+        .enable_debug_log(plugin_debug)
+        .with_temperature(cli.temp.unwrap_or(DEFAULT_TEMPERATURE))
+        .with_top_p(cli.top_p.unwrap_or(1.0));
+    // create a Metadata instance
+    let metadata = builder.build();
+
+    // initialize the core context
+    init_core_context(Some(&[metadata]), None)?;
+
+    // get the plugin version info
+    let plugin_info = llama_core::get_plugin_info()?;
+    log(format!(
+        "[INFO] Wasi-nn-ggml plugin: b{build_number} (commit {commit_id})",
+        build_number = plugin_info.build_number,
+        commit_id = plugin_info.commit_id
+    ));
 
     Ok(())
 }
@@ -1,165 +1,350 @@
+use crate::chat_backend::{BackendError, ChatBackend};
+use crate::conversation::*;
+use crate::json_extract::*;
 use endpoints::{
-    chat::{
-        ChatCompletionObject, ChatCompletionRequest, ChatCompletionRequestMessage,
-        ChatCompletionRole, ChatCompletionUserMessageContent,
-    },
+    chat::{ChatCompletionObject, ChatCompletionRequest, ChatCompletionRole},
     common::Usage,
 };
-use llama_core::LlamaCoreError;
+use once_cell::sync::Lazy;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::Value;
 // use crate::llm_llama_local::chat_inner_async;
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub struct NousToolCall {
     pub name: String,
-    pub arguments: Option<HashMap<String, String>>,
+    /// The arguments as the model wrote them; `arguments_object` is what tools receive.
+    #[serde(default)]
+    pub arguments: Value,
 }
 
-#[allow(non_snake_case)]
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
-pub enum NousContent {
-    Text(String),
-    NousToolCall(NousToolCall),
-}
-trait UsageClone {
-    fn clone(&self) -> Self;
-}
-
-impl UsageClone for Usage {
-    fn clone(&self) -> Self {
-        Usage {
-            prompt_tokens: self.prompt_tokens,
-            completion_tokens: self.completion_tokens,
-            total_tokens: self.total_tokens,
+impl NousToolCall {
+    /// The arguments as an object: `{}` when the model gave none, and parsed when it wrote
+    /// the object out as a JSON string.
+    pub fn arguments_object(&self) -> Value {
+        match &self.arguments {
+            Value::Null => Value::Object(Default::default()),
+            Value::String(s) => match serde_json::from_str::<Value>(s) {
+                Ok(parsed @ Value::Object(_)) => parsed,
+                _ => self.arguments.clone(),
+            },
+            other => other.clone(),
         }
     }
 }
 
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub enum NousContent {
+    Text(String),
+    /// One or more calls, in the order the model wrote them.
+    NousToolCalls(Vec<NousToolCall>),
+}
 #[derive(Debug, Deserialize, Serialize)]
 pub struct NousResponseMessage {
     pub content: NousContent,
+    /// Text the model wrote around its tool calls; empty for a plain-text reply.
+    #[serde(default)]
+    pub commentary: String,
     pub role: ChatCompletionRole,
     pub usage: Usage,
 }
 
+// `Usage` isn't `Clone`, so this one can't be derived
 impl Clone for NousResponseMessage {
     fn clone(&self) -> Self {
         Self {
             content: self.content.clone(),
-            role: self.role.clone(),
-            usage: self.usage.clone(), // Use the custom clone method here
+            commentary: self.commentary.clone(),
+            role: self.role,
+            usage: Usage {
+                prompt_tokens: self.usage.prompt_tokens,
+                completion_tokens: self.usage.completion_tokens,
+                total_tokens: self.usage.total_tokens,
+            },
         }
     }
 }
 
-impl Clone for NousToolCall {
-    fn clone(&self) -> Self {
-        Self {
-            name: self.name.clone(),
-            arguments: self.arguments.clone(),
+impl NousResponseMessage {
+    pub fn content_to_string(&self) -> String {
+        match &self.content {
+            NousContent::Text(text) => text.clone(),
+            NousContent::NousToolCalls(tool_calls) => {
+                let calls = tool_calls
+                    .iter()
+                    .map(|tool_call| {
+                        format!(
+                            "tool_call: {}, arguments: {}",
+                            tool_call.name,
+                            tool_call.arguments_object()
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                if self.commentary.is_empty() {
+                    calls
+                } else {
+                    format!("{}\n{}", self.commentary, calls)
+                }
+            }
         }
     }
 }
 
-impl Clone for NousContent {
-    fn clone(&self) -> Self {
-        match &*self {
-            NousContent::Text(s) => NousContent::Text(s.clone()),
-            NousContent::NousToolCall(t) => NousContent::NousToolCall(t.clone()),
+/// Opening and closing tags models put around tool calls: `<tool_call>` as prompted, and
+/// variants such as `<tool_calls>`, `<toolcall>`, `<function_call>`, the `<tools>` wrapper
+/// of the tool signatures and the `<|tool_call|>` token form.
+static TOOL_CALL_TAG: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)<\|?\s*(/?)\s*(?:tool_?calls?|function_?calls?|tools)\s*\|?>").unwrap()
+});
+
+/// The tool calls in `<tool_call>` blocks anywhere in a reply, and the text around them as
+/// the model's commentary. A block left open runs to the end of the reply, and its calls
+/// are taken only if their JSON is complete, since one cut off at `n_predict` would run
+/// with truncated arguments; a block with no readable call is kept in the commentary.
+pub fn scan_tool_calls(data: &str) -> (String, Vec<NousToolCall>) {
+    let mut commentary: Vec<&str> = Vec::new();
+    let mut calls = Vec::new();
+    // end of the text already taken, and the start of the open block's tag and body
+    let mut pos = 0;
+    let mut open: Option<(usize, usize)> = None;
+    for cap in TOOL_CALL_TAG.captures_iter(data) {
+        let whole = cap.get(0).unwrap();
+        let closing = !cap[1].is_empty();
+        match open {
+            None if closing => {}
+            None => {
+                commentary.push(&data[pos..whole.start()]);
+                open = Some((whole.start(), whole.end()));
+            }
+            Some((tag_start, body_start)) if closing => {
+                take_block(
+                    &data[tag_start..whole.end()],
+                    extract_json_values(&data[body_start..whole.start()]),
+                    &mut commentary,
+                    &mut calls,
+                );
+                pos = whole.end();
+                open = None;
+            }
+            // a new block opens before the last one closed
+            Some((tag_start, body_start)) => {
+                take_block(
+                    &data[tag_start..whole.start()],
+                    extract_json_values(&data[body_start..whole.start()]),
+                    &mut commentary,
+                    &mut calls,
+                );
+                open = Some((whole.start(), whole.end()));
+            }
         }
     }
+    match open {
+        // the reply ended inside the block, so only take calls that weren't cut off
+        Some((tag_start, body_start)) => take_block(
+            &data[tag_start..],
+            complete_json_values(&data[body_start..]),
+            &mut commentary,
+            &mut calls,
+        ),
+        None => commentary.push(&data[pos..]),
+    }
+
+    let commentary = commentary
+        .iter()
+        .map(|text| text.trim())
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<&str>>()
+        .join("\n");
+    (commentary, calls)
 }
-impl NousResponseMessage {
-    pub fn content_to_string(&self) -> String {
-        match &self.content {
-            NousContent::Text(text) => text.clone(),
-            NousContent::NousToolCall(tool_call) => format!(
-                "tool_call: {}, arguments: {}",
-                tool_call.name,
-                tool_call
-                    .arguments
-                    .as_ref()
-                    .unwrap()
-                    .into_iter()
-                    .map(|(arg, val)| format!("{:?}: {:?}", arg, val))
-                    .collect::<Vec<String>>()
-                    .join(", ")
-            ),
-        }
+
+/// Adds the calls among the `values` of a block's body, or the whole `block` to the
+/// commentary if there are none.
+fn take_block<'a>(
+    block: &'a str,
+    values: Vec<Value>,
+    commentary: &mut Vec<&'a str>,
+    calls: &mut Vec<NousToolCall>,
+) {
+    let found = calls_in(values);
+    if found.is_empty() {
+        commentary.push(block);
     }
+    calls.extend(found);
 }
 
-fn extract_json_from_xml_like(xml_like_data: &str) -> Option<String> {
-    let start_tag = "<tool_call>";
-    let end_tag = "</tool_call>";
+/// The calls among the values of one block: each object with a `name`, alone or in a
+/// list, taking its arguments from `arguments` or `parameters`.
+fn calls_in(values: Vec<Value>) -> Vec<NousToolCall> {
+    values
+        .into_iter()
+        .flat_map(|value| match value {
+            Value::Array(items) => items,
+            other => vec![other],
+        })
+        .filter_map(|value| {
+            let name = value["name"].as_str()?.trim().to_string();
+            let arguments = match &value["arguments"] {
+                Value::Null => value["parameters"].clone(),
+                arguments => arguments.clone(),
+            };
+            Some(NousToolCall { name, arguments })
+        })
+        .collect()
+}
 
-    if xml_like_data.trim().starts_with(start_tag) && xml_like_data.trim().ends_with(end_tag) {
-        let start_pos = start_tag.len();
-        let end_pos = xml_like_data.len() - end_tag.len();
-        Some(xml_like_data[start_pos..end_pos].trim().to_string())
-    } else {
-        None
+/// Tool calls if the reply has any, with the text around them as commentary; otherwise
+/// plain text.
+pub fn parse_nous_content(data: &str) -> (NousContent, String) {
+    match scan_tool_calls(data) {
+        (_, calls) if calls.is_empty() => (NousContent::Text(data.to_owned()), String::new()),
+        (commentary, calls) => (NousContent::NousToolCalls(calls), commentary),
     }
 }
 
-pub fn output_nous_response(res_obj: ChatCompletionObject) -> NousResponseMessage {
+pub fn output_nous_response(
+    res_obj: ChatCompletionObject,
+) -> Result<NousResponseMessage, BackendError> {
     let usage = res_obj.usage;
-    let msg_obj = &res_obj.choices[0].message;
-    let role = msg_obj.role.clone(); // Assuming role is clonable
+    let msg_obj = &res_obj
+        .choices
+        .first()
+        .ok_or(BackendError::NoChoices)?
+        .message;
+    let role = msg_obj.role;
 
     let data = &msg_obj.content;
-    println!(" data: {:?}", data.clone());
 
-    let mut res = NousContent::Text(data.to_owned());
-    if let Some(json_str) = extract_json_from_xml_like(data) {
-        if let Ok(tool_call) = serde_json::from_str::<NousToolCall>(&json_str) {
-            res = NousContent::NousToolCall(tool_call);
-        }
-    }
-    NousResponseMessage {
-        content: res,
+    let (content, commentary) = parse_nous_content(data);
+    Ok(NousResponseMessage {
+        content,
+        commentary,
         role,
         usage,
-    }
+    })
 }
 
+/// Continues `conversation` with `user_input` and records the exchange in its history.
 pub async fn chat_completions_partial(
-    chat_request: &mut ChatCompletionRequest,
+    backend: &dyn ChatBackend,
+    request_template: &ChatCompletionRequest,
+    conversation: &mut Conversation,
     user_input: &str,
-) -> Result<NousResponseMessage, LlamaCoreError> {
-    let user_message = ChatCompletionRequestMessage::new_user_message(
-        ChatCompletionUserMessageContent::Text(user_input.to_string()),
-        None,
-    );
-
-    chat_request.messages.push(user_message);
+) -> Result<NousResponseMessage, BackendError> {
+    let mut chat_request =
+        request_from_template(request_template, conversation.build_messages(user_input));
 
-    let res = llama_core::chat::chat_completions(chat_request).await?;
+    let res = backend.chat(&mut chat_request).await?;
 
-    let content = output_nous_response(res);
+    let content = output_nous_response(res)?;
+    conversation.record_turn(user_input, &content.content_to_string());
 
     Ok(content)
 }
 
+/// A one-shot call made of exactly one system message and one user message.
 pub async fn chat_completions_full(
-    chat_request: &mut ChatCompletionRequest,
+    backend: &dyn ChatBackend,
+    request_template: &ChatCompletionRequest,
     system_prompt: &str,
     user_input: &str,
-) -> Result<NousResponseMessage, LlamaCoreError> {
-    let system_message = ChatCompletionRequestMessage::new_system_message(system_prompt, None);
+) -> Result<NousResponseMessage, BackendError> {
+    let messages = Conversation::new(system_prompt).build_messages(user_input);
+    let mut chat_request = request_from_template(request_template, messages);
 
-    chat_request.messages.push(system_message);
-    let user_message = ChatCompletionRequestMessage::new_user_message(
-        ChatCompletionUserMessageContent::Text(user_input.to_string()),
-        None,
-    );
+    let res = backend.chat(&mut chat_request).await?;
 
-    chat_request.messages.push(user_message);
+    let content = output_nous_response(res)?;
 
-    let res = llama_core::chat::chat_completions(chat_request).await?;
+    Ok(content)
+}
 
-    let content = output_nous_response(res);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
 
-    Ok(content)
+    fn call(name: &str, arguments: Value) -> NousToolCall {
+        NousToolCall {
+            name: name.to_string(),
+            arguments,
+        }
+    }
+
+    #[test]
+    fn scans_tool_calls() {
+        let cases: &[(&str, &str, &str, Vec<NousToolCall>)] = &[
+            (
+                "a single block with a trailing newline",
+                "<tool_call>\n{\"name\": \"a\", \"arguments\": {\"x\": 1}}\n</tool_call>\n",
+                "",
+                vec![call("a", json!({"x": 1}))],
+            ),
+            (
+                "preamble and trailing text",
+                "Sure, let me search.\n<tool_call>{\"name\": \"search\", \"arguments\": {\"query\": \"q\"}}</tool_call> then read",
+                "Sure, let me search.\nthen read",
+                vec![call("search", json!({"query": "q"}))],
+            ),
+            (
+                "the <tools> wrapper",
+                "<tools>\n{\"name\": \"a\", \"arguments\": {}}\n{\"name\": \"b\", \"arguments\": {\"u\": [1, 2]}}\n</tools>",
+                "",
+                vec![call("a", json!({})), call("b", json!({"u": [1, 2]}))],
+            ),
+            (
+                "the <|tool_call|> form with a list of calls",
+                "<|tool_call|>[{\"name\": \"x\", \"arguments\": {}}]</|tool_call|>",
+                "",
+                vec![call("x", json!({}))],
+            ),
+            (
+                "several blocks, the first left open, with parameters",
+                "<tool_call>{\"name\": \"a\"}<tool_call>{\"name\": \"b\", \"parameters\": {\"k\": true}}</tool_call>",
+                "",
+                vec![call("a", Value::Null), call("b", json!({"k": true}))],
+            ),
+            (
+                "an unclosed final block",
+                "Thought: hmm\n<tool_call>\n{\"name\": \"code\", \"arguments\": {\"key_points\": \"compute 2 + 2\"}}",
+                "Thought: hmm",
+                vec![call("code", json!({"key_points": "compute 2 + 2"}))],
+            ),
+            (
+                "an unclosed final block cut off mid-call",
+                "Thought: hmm\n<tool_call>\n{\"name\": \"code\", \"arguments\": {\"key_points\": \"compute the",
+                "Thought: hmm\n<tool_call>\n{\"name\": \"code\", \"arguments\": {\"key_points\": \"compute the",
+                vec![],
+            ),
+            (
+                "an unparsable block kept as commentary",
+                "I can't use <tool_call> here, final answer: 42",
+                "I can't use\n<tool_call> here, final answer: 42",
+                vec![],
+            ),
+        ];
+        for (what, reply, commentary, calls) in cases {
+            let (found_commentary, found_calls) = scan_tool_calls(reply);
+            assert_eq!(&found_calls, calls, "{}", what);
+            assert_eq!(found_commentary, *commentary, "{}", what);
+        }
+    }
+
+    #[test]
+    fn text_without_calls_is_kept_as_is() {
+        let reply = "The answer is 42.\n";
+        assert_eq!(
+            parse_nous_content(reply),
+            (NousContent::Text(reply.to_string()), String::new())
+        );
+    }
+
+    #[test]
+    fn arguments_written_as_a_string_are_parsed() {
+        let call = call("a", json!("{\"x\": 1}"));
+        assert_eq!(call.arguments_object(), json!({"x": 1}));
+    }
 }
@@ -0,0 +1,248 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// One node of a plan: a task for a single tool, run once the steps it depends on are done.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PlanStep {
+    pub id: String,
+    pub description: String,
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// The tool the planner meant the step for; a hint to the tool router, not a constraint.
+    #[serde(default)]
+    pub tool: Option<String>,
+}
+
+/// A dependency graph of steps, kept in the order the planner listed them.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Plan {
+    pub steps: Vec<PlanStep>,
+}
+
+impl Plan {
+    pub fn new(steps: Vec<PlanStep>) -> Self {
+        Plan { steps }
+    }
+
+    /// A plan of plain task descriptions, each depending on the one before it.
+    pub fn sequential(descriptions: Vec<String>) -> Self {
+        let steps = descriptions
+            .into_iter()
+            .enumerate()
+            .map(|(i, description)| PlanStep {
+                id: format!("s{}", i + 1),
+                description,
+                depends_on: if i == 0 {
+                    vec![]
+                } else {
+                    vec![format!("s{}", i)]
+                },
+                tool: None,
+            })
+            .collect();
+        Plan { steps }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&PlanStep> {
+        self.steps.iter().find(|s| s.id == id)
+    }
+
+    /// Checks that ids are unique, every dependency exists and there is no cycle.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut ids = HashSet::new();
+        for step in &self.steps {
+            if !ids.insert(step.id.as_str()) {
+                return Err(format!("step id '{}' is used more than once", step.id));
+            }
+        }
+        for step in &self.steps {
+            if let Some(missing) = step.depends_on.iter().find(|d| !ids.contains(d.as_str())) {
+                return Err(format!(
+                    "step '{}' depends on unknown step '{}'",
+                    step.id, missing
+                ));
+            }
+        }
+
+        let mut done = HashSet::new();
+        while done.len() < self.steps.len() {
+            let ready = self.ready_steps(&done, &HashSet::new());
+            if ready.is_empty() {
+                return Err("the steps depend on each other in a cycle".to_string());
+            }
+            done.extend(ready.into_iter().map(|s| s.id.clone()));
+        }
+        Ok(())
+    }
+
+    /// Steps not yet `done` or `started` whose dependencies are all `done`.
+    pub fn ready_steps(&self, done: &HashSet<String>, started: &HashSet<String>) -> Vec<&PlanStep> {
+        self.steps
+            .iter()
+            .filter(|s| !done.contains(&s.id) && !started.contains(&s.id))
+            .filter(|s| s.depends_on.iter().all(|d| done.contains(d)))
+            .collect()
+    }
+
+    /// Steps no other step depends on; together their outputs answer the task.
+    pub fn final_steps(&self) -> Vec<&PlanStep> {
+        let needed: HashSet<&str> = self
+            .steps
+            .iter()
+            .flat_map(|s| s.depends_on.iter().map(|d| d.as_str()))
+            .collect();
+        self.steps
+            .iter()
+            .filter(|s| !needed.contains(s.id.as_str()))
+            .collect()
+    }
+}
+
+/// The prompt for one step: its task, the tool the planner had in mind, and the outputs of
+/// exactly the steps it depends on.
+pub fn step_input(step: &PlanStep, outputs: &HashMap<String, String>) -> String {
+    let mut input = step.description.clone();
+    if let Some(tool) = &step.tool {
+        input.push_str(&format!("\n\nSuggested tool: {}", tool));
+    }
+    if !step.depends_on.is_empty() {
+        let results = step
+            .depends_on
+            .iter()
+            .map(|id| {
+                format!(
+                    "[{}] {}",
+                    id,
+                    outputs.get(id).map(|s| s.as_str()).unwrap_or_default()
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        input.push_str(&format!(
+            "\n\nResults of the steps this task builds on:\n{}",
+            results
+        ));
+    }
+    input
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(id: &str, depends_on: &[&str]) -> PlanStep {
+        PlanStep {
+            id: id.to_string(),
+            description: format!("do {}", id),
+            depends_on: depends_on.iter().map(|d| d.to_string()).collect(),
+            tool: None,
+        }
+    }
+
+    fn ids(steps: Vec<&PlanStep>) -> Vec<&str> {
+        steps.iter().map(|s| s.id.as_str()).collect()
+    }
+
+    fn set(ids: &[&str]) -> HashSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn validate() {
+        let cases = [
+            ("a chain", vec![step("a", &[]), step("b", &["a"])], Ok(())),
+            (
+                "a diamond",
+                vec![
+                    step("a", &[]),
+                    step("b", &["a"]),
+                    step("c", &["a"]),
+                    step("d", &["b", "c"]),
+                ],
+                Ok(()),
+            ),
+            (
+                "a duplicate id",
+                vec![step("a", &[]), step("a", &[])],
+                Err("step id 'a' is used more than once"),
+            ),
+            (
+                "an unknown dependency",
+                vec![step("a", &[]), step("b", &["x"])],
+                Err("step 'b' depends on unknown step 'x'"),
+            ),
+            (
+                "a cycle",
+                vec![step("a", &[]), step("b", &["c"]), step("c", &["b"])],
+                Err("the steps depend on each other in a cycle"),
+            ),
+            (
+                "a step depending on itself",
+                vec![step("a", &["a"])],
+                Err("the steps depend on each other in a cycle"),
+            ),
+        ];
+        for (what, steps, expected) in cases {
+            assert_eq!(
+                Plan::new(steps).validate(),
+                expected.map_err(str::to_string),
+                "{}",
+                what
+            );
+        }
+    }
+
+    #[test]
+    fn ready_steps() {
+        let plan = Plan::new(vec![
+            step("a", &[]),
+            step("b", &[]),
+            step("c", &["a"]),
+            step("d", &["a", "b"]),
+        ]);
+        let cases: [(&[&str], &[&str], &[&str]); 4] = [
+            (&[], &[], &["a", "b"]),
+            (&[], &["a"], &["b"]),
+            (&["a"], &["b"], &["c"]),
+            (&["a", "b"], &["b"], &["c", "d"]),
+        ];
+        for (done, started, expected) in cases {
+            assert_eq!(
+                ids(plan.ready_steps(&set(done), &set(started))),
+                expected,
+                "done {:?}, started {:?}",
+                done,
+                started
+            );
+        }
+        assert_eq!(ids(plan.final_steps()), ["c", "d"]);
+    }
+
+    #[test]
+    fn step_input_carries_the_tool_and_dependency_outputs() {
+        let mut dependent = step("c", &["a", "b"]);
+        dependent.tool = Some("search_with_bing".to_string());
+        let outputs = HashMap::from([
+            ("a".to_string(), "one".to_string()),
+            ("b".to_string(), "two".to_string()),
+        ]);
+
+        assert_eq!(step_input(&step("a", &[]), &outputs), "do a");
+        assert_eq!(
+            step_input(&dependent, &outputs),
+            "do c\n\nSuggested tool: search_with_bing\n\n\
+             Results of the steps this task builds on:\n[a] one\n[b] two"
+        );
+    }
+
+    #[test]
+    fn sequential_plans_chain_their_steps() {
+        let plan = Plan::sequential(vec!["first".to_string(), "second".to_string()]);
+        assert_eq!(plan.steps[1].depends_on, ["s1"]);
+        assert_eq!(plan.validate(), Ok(()));
+    }
+}
@@ -0,0 +1,391 @@
+use crate::conversation::AgentRole;
+use crate::error::*;
+use crate::immutable_agent::RunMode;
+use crate::planning::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Where a run is in its life; saved with every checkpoint so `resume` knows what to do next.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "phase", rename_all = "snake_case")]
+pub enum RunPhase {
+    /// No approved plan yet; resuming draws it up again.
+    Planning,
+    /// Working through the plan; `steps` are the ids in flight.
+    Executing {
+        steps: Vec<String>,
+    },
+    /// Judging results, either between rounds or before the answer is handed back.
+    Verifying,
+    Done {
+        answer: String,
+    },
+}
+
+/// Something that happened during a run, recorded as it happens.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RunEvent {
+    LlmCall {
+        role: AgentRole,
+        input: String,
+        reply: String,
+    },
+    ToolResult {
+        tool: String,
+        arguments: Value,
+        result: String,
+    },
+}
+
+/// Everything needed to pick a plan-and-execute run up where it stopped.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RunState {
+    pub run_id: String,
+    pub task: String,
+    /// What the planner is working from: the task, plus the reviewer's comment after a
+    /// rejected answer.
+    pub input: String,
+    pub mode: RunMode,
+    pub phase: RunPhase,
+    pub plan: Option<Plan>,
+    /// Outputs of the steps that finished, keyed by step id.
+    pub outputs: HashMap<String, String>,
+    /// Ids of the finished steps, in the order they finished.
+    pub completed: Vec<String>,
+    pub replans: usize,
+    /// Kept in the run's events file rather than its checkpoint, so saving after each
+    /// event only appends that event.
+    #[serde(skip)]
+    pub events: Vec<RunEvent>,
+}
+
+impl RunState {
+    pub fn new(task: &str, mode: RunMode) -> Self {
+        RunState {
+            run_id: chrono::Local::now().format("%Y%m%d-%H%M%S-%3f").to_string(),
+            task: task.to_string(),
+            input: task.to_string(),
+            mode,
+            phase: RunPhase::Planning,
+            plan: None,
+            outputs: HashMap::new(),
+            completed: Vec::new(),
+            replans: 0,
+            events: Vec::new(),
+        }
+    }
+
+    /// Starts over from planning with new input, keeping the event log.
+    pub fn restart(&mut self, input: &str) {
+        self.input = input.to_string();
+        self.phase = RunPhase::Planning;
+        self.plan = None;
+        self.outputs.clear();
+        self.completed.clear();
+        self.replans = 0;
+    }
+
+    pub fn step_started(&mut self, id: &str) {
+        match &mut self.phase {
+            RunPhase::Executing { steps } => steps.push(id.to_string()),
+            phase => {
+                *phase = RunPhase::Executing {
+                    steps: vec![id.to_string()],
+                }
+            }
+        }
+    }
+
+    pub fn step_finished(&mut self, id: &str, output: &str) {
+        if let RunPhase::Executing { steps } = &mut self.phase {
+            steps.retain(|s| s != id);
+        }
+        self.outputs.insert(id.to_string(), output.to_string());
+        if !self.completed.iter().any(|c| c == id) {
+            self.completed.push(id.to_string());
+        }
+    }
+
+    /// Forgets the output of `step_id` and of every step that builds on it, optionally
+    /// replacing the step's description, so the next `resume` runs them again.
+    pub fn rewind_to(&mut self, step_id: &str, edited: Option<&str>) -> AgentResult<()> {
+        let plan = self
+            .plan
+            .as_mut()
+            .ok_or_else(|| AgentError::Resume(format!("run '{}' has no plan yet", self.run_id)))?;
+        let step = plan
+            .steps
+            .iter_mut()
+            .find(|s| s.id == step_id)
+            .ok_or_else(|| {
+                AgentError::Resume(format!("run '{}' has no step '{}'", self.run_id, step_id))
+            })?;
+        if let Some(edited) = edited {
+            step.description = edited.to_string();
+        }
+
+        let mut stale = HashSet::from([step_id.to_string()]);
+        loop {
+            let before = stale.len();
+            for step in &plan.steps {
+                if step.depends_on.iter().any(|d| stale.contains(d)) {
+                    stale.insert(step.id.clone());
+                }
+            }
+            if stale.len() == before {
+                break;
+            }
+        }
+
+        self.outputs.retain(|id, _| !stale.contains(id));
+        self.completed.retain(|id| !stale.contains(id));
+        self.phase = RunPhase::Executing { steps: vec![] };
+        Ok(())
+    }
+}
+
+/// A directory of run checkpoints: for each run, a JSON file with its state and a JSON
+/// Lines file of its events.
+#[derive(Debug, Clone)]
+pub struct RunStore {
+    dir: PathBuf,
+    /// How many of each run's events are already in its events file.
+    events_saved: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl RunStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        RunStore {
+            dir: dir.into(),
+            events_saved: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn path(&self, run_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", run_id))
+    }
+
+    pub fn events_path(&self, run_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.events.jsonl", run_id))
+    }
+
+    /// Writes `state` next to its checkpoint and then moves it over, so an interruption
+    /// never leaves a half-written file behind.
+    pub fn save(&self, state: &RunState) -> AgentResult<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        self.append_events(state)?;
+        let json =
+            serde_json::to_string_pretty(state).map_err(|e| AgentError::Resume(e.to_string()))?;
+        let path = self.path(&state.run_id);
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Appends the events of `state` not yet in its events file. The first save of a run
+    /// this store hasn't loaded writes the file afresh.
+    fn append_events(&self, state: &RunState) -> AgentResult<()> {
+        let mut events_saved = self.events_saved.lock().unwrap();
+        let saved = events_saved
+            .get(&state.run_id)
+            .copied()
+            .filter(|saved| *saved <= state.events.len());
+        let mut options = OpenOptions::new();
+        match saved {
+            Some(_) => options.append(true),
+            None => options.write(true).truncate(true),
+        };
+        let mut file = options.create(true).open(self.events_path(&state.run_id))?;
+
+        let mut lines = String::new();
+        for event in &state.events[saved.unwrap_or(0)..] {
+            let line =
+                serde_json::to_string(event).map_err(|e| AgentError::Resume(e.to_string()))?;
+            lines.push_str(&line);
+            lines.push('\n');
+        }
+        file.write_all(lines.as_bytes())?;
+        events_saved.insert(state.run_id.clone(), state.events.len());
+        Ok(())
+    }
+
+    pub fn load(&self, run_id: &str) -> AgentResult<RunState> {
+        let path = self.path(run_id);
+        let json = std::fs::read_to_string(&path)
+            .map_err(|e| AgentError::Resume(format!("cannot read {}: {}", path.display(), e)))?;
+        let mut state = serde_json::from_str::<RunState>(&json).map_err(|e| {
+            AgentError::Resume(format!("{} is not a run checkpoint: {}", path.display(), e))
+        })?;
+
+        let events_path = self.events_path(run_id);
+        if events_path.exists() {
+            let events = std::fs::read_to_string(&events_path)?;
+            for (n, line) in events.lines().enumerate() {
+                let event = serde_json::from_str::<RunEvent>(line).map_err(|e| {
+                    AgentError::Resume(format!("{} line {}: {}", events_path.display(), n + 1, e))
+                })?;
+                state.events.push(event);
+            }
+        }
+        self.events_saved
+            .lock()
+            .unwrap()
+            .insert(state.run_id.clone(), state.events.len());
+        Ok(state)
+    }
+
+    /// Ids of the saved runs, oldest first.
+    pub fn list(&self) -> AgentResult<Vec<String>> {
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut ids = std::fs::read_dir(&self.dir)?
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                match path.extension()?.to_str()? {
+                    "json" => Some(path.file_stem()?.to_string_lossy().to_string()),
+                    _ => None,
+                }
+            })
+            .collect::<Vec<String>>();
+        ids.sort();
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(id: &str, depends_on: &[&str]) -> PlanStep {
+        PlanStep {
+            id: id.to_string(),
+            description: format!("do {}", id),
+            depends_on: depends_on.iter().map(|d| d.to_string()).collect(),
+            tool: None,
+        }
+    }
+
+    /// A run with s1 -> s2 -> s3 and an unrelated s4, all finished.
+    fn finished_run() -> RunState {
+        let mut run = RunState::new("a task", RunMode::PlanAndExecute);
+        run.plan = Some(Plan::new(vec![
+            step("s1", &[]),
+            step("s2", &["s1"]),
+            step("s3", &["s2"]),
+            step("s4", &[]),
+        ]));
+        for id in ["s1", "s4", "s2", "s3"] {
+            run.step_started(id);
+            run.step_finished(id, &format!("output of {}", id));
+        }
+        run
+    }
+
+    fn temp_store(name: &str) -> RunStore {
+        let dir = std::env::temp_dir().join(format!("llama-agent-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        RunStore::new(dir)
+    }
+
+    #[test]
+    fn steps_are_tracked_in_flight_and_done() {
+        let mut run = RunState::new("a task", RunMode::PlanAndExecute);
+        run.step_started("s1");
+        run.step_started("s2");
+        run.step_finished("s1", "one");
+        assert_eq!(
+            run.phase,
+            RunPhase::Executing {
+                steps: vec!["s2".to_string()]
+            }
+        );
+        run.step_finished("s1", "one again");
+        assert_eq!(run.completed, ["s1"]);
+        assert_eq!(run.outputs["s1"], "one again");
+    }
+
+    #[test]
+    fn rewinding_a_step_makes_what_builds_on_it_stale() {
+        let mut run = finished_run();
+        run.rewind_to("s2", Some("do s2 differently")).unwrap();
+
+        assert_eq!(run.completed, ["s1", "s4"]);
+        let mut kept = run.outputs.keys().cloned().collect::<Vec<String>>();
+        kept.sort();
+        assert_eq!(kept, ["s1", "s4"]);
+        assert_eq!(
+            run.plan.as_ref().unwrap().steps[1].description,
+            "do s2 differently"
+        );
+        assert_eq!(run.phase, RunPhase::Executing { steps: vec![] });
+
+        let mut run = finished_run();
+        run.rewind_to("s1", None).unwrap();
+        assert_eq!(run.completed, ["s4"]);
+        assert_eq!(run.plan.as_ref().unwrap().steps[0].description, "do s1");
+    }
+
+    #[test]
+    fn rewinding_needs_a_known_step() {
+        let mut run = finished_run();
+        let error = run.rewind_to("s9", None).unwrap_err().to_string();
+        assert!(error.contains("has no step 's9'"), "{}", error);
+        assert_eq!(run.completed.len(), 4);
+
+        let mut run = RunState::new("a task", RunMode::PlanAndExecute);
+        let error = run.rewind_to("s1", None).unwrap_err().to_string();
+        assert!(error.contains("has no plan yet"), "{}", error);
+    }
+
+    #[test]
+    fn saved_runs_load_with_their_events() {
+        let store = temp_store("runs");
+        let mut run = finished_run();
+        let event = |n: usize| RunEvent::LlmCall {
+            role: AgentRole::Planner,
+            input: format!("input {}", n),
+            reply: format!("reply {}", n),
+        };
+        run.events.push(event(1));
+        store.save(&run).unwrap();
+        run.events.push(event(2));
+        run.events.push(RunEvent::ToolResult {
+            tool: "search_with_bing".to_string(),
+            arguments: serde_json::json!({ "query": "q" }),
+            result: "r".to_string(),
+        });
+        store.save(&run).unwrap();
+
+        let events = std::fs::read_to_string(store.events_path(&run.run_id)).unwrap();
+        assert_eq!(events.lines().count(), 3);
+        let checkpoint = std::fs::read_to_string(store.path(&run.run_id)).unwrap();
+        assert!(!checkpoint.contains("reply 1"));
+        assert_eq!(store.list().unwrap(), [run.run_id.clone()]);
+
+        // a fresh store, as in a later process, appends after what it loaded
+        let store = RunStore::new(store.dir.clone());
+        let mut loaded = store.load(&run.run_id).unwrap();
+        assert_eq!(loaded, run);
+        loaded.events.push(event(3));
+        store.save(&loaded).unwrap();
+        assert_eq!(store.load(&run.run_id).unwrap(), loaded);
+
+        std::fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn loading_a_missing_run_fails() {
+        let store = temp_store("no-runs");
+        assert!(store.list().unwrap().is_empty());
+        let error = store.load("nope").unwrap_err().to_string();
+        assert!(error.contains("cannot read"), "{}", error);
+    }
+}
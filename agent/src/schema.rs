@@ -0,0 +1,194 @@
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+/// A reply the model is asked to give as JSON, checked against `schema()` before it is
+/// deserialized.
+pub trait StructuredReply: DeserializeOwned {
+    /// JSON schema of the reply, in the subset `validate` understands.
+    fn schema() -> Value;
+}
+
+/// Checks `value` against `schema`, supporting `type` (a name or a list of names),
+/// `properties`, `required`, `items`, `enum` and `anyOf`. The error names the path of
+/// the first offending value, e.g. `steps_to_take[1].id`, or `what` for the value itself.
+pub fn validate(schema: &Value, value: &Value, what: &str) -> Result<(), String> {
+    check(schema, value, "", what)
+}
+
+fn check(schema: &Value, value: &Value, path: &str, what: &str) -> Result<(), String> {
+    let at = |path: &str| {
+        if path.is_empty() {
+            what.to_string()
+        } else {
+            format!("`{}`", path)
+        }
+    };
+
+    if let Some(options) = schema["anyOf"].as_array() {
+        let errors = options
+            .iter()
+            .filter_map(|option| check(option, value, path, what).err())
+            .collect::<Vec<String>>();
+        if errors.len() == options.len() {
+            return Err(errors.join("; or "));
+        }
+    }
+
+    let types = match &schema["type"] {
+        Value::String(ty) => vec![ty.as_str()],
+        Value::Array(tys) => tys.iter().filter_map(Value::as_str).collect(),
+        _ => vec![],
+    };
+    if !types.is_empty() && !types.iter().any(|ty| is_type(value, ty)) {
+        return Err(format!(
+            "{} should be {}, found {}",
+            at(path),
+            types.join(" or "),
+            describe(value)
+        ));
+    }
+
+    if let Some(allowed) = schema["enum"].as_array() {
+        if !allowed.contains(value) {
+            return Err(format!(
+                "{} should be one of {}, found {}",
+                at(path),
+                allowed
+                    .iter()
+                    .map(Value::to_string)
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                value
+            ));
+        }
+    }
+
+    if let Value::Object(fields) = value {
+        for name in schema["required"].as_array().into_iter().flatten() {
+            let name = name.as_str().unwrap_or_default();
+            if fields.get(name).is_none_or(Value::is_null) {
+                return Err(format!("{} is missing `{}`", at(path), join(path, name)));
+            }
+        }
+        if let Some(properties) = schema["properties"].as_object() {
+            for (name, property) in properties {
+                match fields.get(name) {
+                    Some(Value::Null) | None => {}
+                    Some(field) => check(property, field, &join(path, name), what)?,
+                }
+            }
+        }
+    }
+
+    if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+        for (i, item) in items.iter().enumerate() {
+            check(item_schema, item, &format!("{}[{}]", path, i), what)?;
+        }
+    }
+    Ok(())
+}
+
+fn join(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+fn is_type(value: &Value, ty: &str) -> bool {
+    match ty {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+fn describe(value: &Value) -> String {
+    match value {
+        Value::Object(_) => "an object".to_string(),
+        Value::Array(_) => "a list".to_string(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn errors_name_the_offending_value() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "query": { "type": "string" },
+                "count": { "type": "integer" },
+                "unit": { "enum": ["km", "mi"] },
+                "tags": { "type": "array", "items": { "type": "string" } },
+                "filter": {
+                    "type": "object",
+                    "properties": { "site": { "type": ["string", "null"] } },
+                    "required": ["site"]
+                }
+            },
+            "required": ["query"]
+        });
+        let cases = [
+            (json!({}), "the call is missing `query`"),
+            (json!({ "query": null }), "the call is missing `query`"),
+            (json!("rust"), "the call should be object, found \"rust\""),
+            (json!({ "query": 7 }), "`query` should be string, found 7"),
+            (
+                json!({ "query": "q", "count": 1.5 }),
+                "`count` should be integer, found 1.5",
+            ),
+            (
+                json!({ "query": "q", "unit": "m" }),
+                "`unit` should be one of \"km\", \"mi\", found \"m\"",
+            ),
+            (
+                json!({ "query": "q", "tags": ["a", 2] }),
+                "`tags[1]` should be string, found 2",
+            ),
+            (
+                json!({ "query": "q", "filter": {} }),
+                "`filter` is missing `filter.site`",
+            ),
+            (
+                json!({ "query": "q", "filter": { "site": [] } }),
+                "`filter.site` should be string or null, found a list",
+            ),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(
+                validate(&schema, &value, "the call").unwrap_err(),
+                expected,
+                "{}",
+                value
+            );
+        }
+        assert!(validate(
+            &schema,
+            &json!({ "query": "q", "count": 3, "unit": "km" }),
+            "the call"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn any_of_reports_every_option() {
+        let schema = json!({ "anyOf": [{ "type": "string" }, { "type": "array" }] });
+        assert!(validate(&schema, &json!("a"), "it").is_ok());
+        assert!(validate(&schema, &json!([]), "it").is_ok());
+        assert_eq!(
+            validate(&schema, &json!(1), "it").unwrap_err(),
+            "it should be string, found 1; or it should be array, found 1"
+        );
+    }
+}
@@ -0,0 +1,334 @@
+use crate::{
+    wording_too_strong_PLANNING, CODE_PYTHON_PROMPT, CODING_FAIL_TEMPLATE, CODING_HISTORY_TEMPLATE,
+    CODING_INCORRECT_TEMPLATE, CODING_INVALID_TEMPLATE, CODING_REJECTED_TEMPLATE,
+    CODING_RETRY_TEMPLATE, CODING_START_TEMPLATE, CODING_SUCCESS_TEMPLATE, EVAL_JUDGE_PROMPT,
+    FURTER_TASK_BY_TOOLCALL_PROMPT, GROUP_CHAT_MEMBER_PROMPT, GROUP_CHAT_SELECT_SPEAKER_PROMPT,
+    IS_TERMINATION_PROMPT, NEXT_STEP_BY_TOOLCALL_PROMPT, NEXT_STEP_PLANNING_PROMPT, REACT_PROMPT,
+    REPLANNING_PROMPT, STRUCTURED_RETRY_TEMPLATE, SUMMARIZE_CHAT_HISTORY_PROMPT,
+    TOOL_RETRY_TEMPLATE,
+};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Every template the agent renders, with the variables it is rendered with.
+/// A template may use any of its variables as `{{name}}`, and no others.
+pub const TEMPLATE_VARIABLES: &[(&str, &[&str])] = &[
+    ("planning", &["tool_list"]),
+    ("replanning", &["tool_list"]),
+    ("dispatcher", &TOOL_CALL_SECTIONS),
+    ("tool_router", &TOOL_CALL_SECTIONS),
+    ("termination", &[]),
+    ("coder", &[]),
+    ("summarize", &[]),
+    ("react", &TOOL_CALL_SECTIONS),
+    ("group_member", &["name", "roster", "termination_keyword"]),
+    ("select_speaker", &["roster"]),
+    ("coding_start", &["task"]),
+    ("coding_invalid", &[]),
+    ("coding_success", &["code", "result"]),
+    ("coding_incorrect", &["code", "result"]),
+    ("coding_fail", &["code", "error"]),
+    ("coding_history", &["task", "summary"]),
+    ("coding_rejected", &["code", "comment"]),
+    ("coding_retry", &["error"]),
+    ("eval_judge", &["task", "expected"]),
+    ("structured_retry", &["error", "schema"]),
+    ("tool_retry", &["task", "calls", "error"]),
+];
+
+const TOOL_CALL_SECTIONS: [&str; 3] = ["tool_signatures", "tool_examples", "tool_call_format"];
+
+/// The variables a template must use: without them the model is never shown the tools it
+/// is asked to plan with or call.
+pub const REQUIRED_VARIABLES: &[(&str, &[&str])] = &[
+    ("planning", &["tool_list"]),
+    ("replanning", &["tool_list"]),
+    ("dispatcher", &["tool_signatures", "tool_call_format"]),
+    ("tool_router", &["tool_signatures", "tool_call_format"]),
+    ("react", &["tool_signatures", "tool_call_format"]),
+];
+
+fn builtin_source(name: &str) -> Option<String> {
+    let source = match name {
+        "planning" => NEXT_STEP_PLANNING_PROMPT.to_string(),
+        "replanning" => REPLANNING_PROMPT.to_string(),
+        "dispatcher" => NEXT_STEP_BY_TOOLCALL_PROMPT.to_string(),
+        "tool_router" => FURTER_TASK_BY_TOOLCALL_PROMPT.to_string(),
+        "termination" => IS_TERMINATION_PROMPT.to_string(),
+        "coder" => CODE_PYTHON_PROMPT.to_string(),
+        "summarize" => SUMMARIZE_CHAT_HISTORY_PROMPT.to_string(),
+        "react" => REACT_PROMPT.to_string(),
+        "group_member" => GROUP_CHAT_MEMBER_PROMPT.to_string(),
+        "select_speaker" => GROUP_CHAT_SELECT_SPEAKER_PROMPT.to_string(),
+        "coding_start" => CODING_START_TEMPLATE.to_string(),
+        "coding_invalid" => CODING_INVALID_TEMPLATE.to_string(),
+        "coding_success" => CODING_SUCCESS_TEMPLATE.to_string(),
+        "coding_incorrect" => CODING_INCORRECT_TEMPLATE.to_string(),
+        "coding_fail" => CODING_FAIL_TEMPLATE.to_string(),
+        "coding_history" => CODING_HISTORY_TEMPLATE.to_string(),
+        "coding_rejected" => CODING_REJECTED_TEMPLATE.to_string(),
+        "coding_retry" => CODING_RETRY_TEMPLATE.to_string(),
+        "eval_judge" => EVAL_JUDGE_PROMPT.to_string(),
+        "structured_retry" => STRUCTURED_RETRY_TEMPLATE.to_string(),
+        "tool_retry" => TOOL_RETRY_TEMPLATE.to_string(),
+        _ => return None,
+    };
+    Some(source)
+}
+
+/// Other wordings shipped with the crate, which a config can swap in by name to compare
+/// them with the defaults.
+pub const ALTERNATIVE_TEMPLATES: &[&str] = &["wording_too_strong_planning"];
+
+pub fn alternative_source(name: &str) -> Option<&'static str> {
+    match name {
+        "wording_too_strong_planning" => Some(wording_too_strong_PLANNING),
+        _ => None,
+    }
+}
+
+pub fn template_variables(name: &str) -> Option<&'static [&'static str]> {
+    TEMPLATE_VARIABLES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, vars)| *vars)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TemplateError {
+    #[error("unknown template '{0}'")]
+    UnknownTemplate(String),
+    #[error("template '{template}' has an unclosed '{{{{' at byte {offset}")]
+    Unclosed { template: String, offset: usize },
+    #[error(
+        "template '{template}' uses '{{{{{variable}}}}}', but it is rendered with: {available}"
+    )]
+    UnknownVariable {
+        template: String,
+        variable: String,
+        available: String,
+    },
+    #[error("template '{template}' must use '{{{{{variable}}}}}'")]
+    MissingVariable { template: String, variable: String },
+    #[error("failed to read template file {path}: {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Text(String),
+    Variable(String),
+}
+
+/// A template parsed into literal text and `{{variable}}` placeholders.
+#[derive(Debug, Clone)]
+pub struct PromptTemplate {
+    segments: Vec<Segment>,
+}
+
+impl PromptTemplate {
+    /// Parses `source`, checking it only uses the variables `name` is rendered with.
+    pub fn parse(name: &str, source: &str) -> Result<Self, TemplateError> {
+        let available = template_variables(name)
+            .ok_or_else(|| TemplateError::UnknownTemplate(name.to_string()))?;
+
+        let mut segments = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            let offset = source.len() - rest.len() + start;
+            let end = rest[start..]
+                .find("}}")
+                .ok_or_else(|| TemplateError::Unclosed {
+                    template: name.to_string(),
+                    offset,
+                })?;
+            let variable = rest[start + 2..start + end].trim();
+            if !available.contains(&variable) {
+                return Err(TemplateError::UnknownVariable {
+                    template: name.to_string(),
+                    variable: variable.to_string(),
+                    available: available.join(", "),
+                });
+            }
+            segments.push(Segment::Variable(variable.to_string()));
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+
+        let required = REQUIRED_VARIABLES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map_or(&[][..], |(_, vars)| *vars);
+        for variable in required {
+            if !segments
+                .iter()
+                .any(|s| matches!(s, Segment::Variable(v) if v == variable))
+            {
+                return Err(TemplateError::MissingVariable {
+                    template: name.to_string(),
+                    variable: variable.to_string(),
+                });
+            }
+        }
+        Ok(PromptTemplate { segments })
+    }
+
+    /// Fills in the placeholders from `vars`; a variable without a value is left as written.
+    pub fn render(&self, vars: &[(&str, &str)]) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.clone(),
+                Segment::Variable(name) => vars
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, value)| value.to_string())
+                    .unwrap_or_else(|| format!("{{{{{}}}}}", name)),
+            })
+            .collect()
+    }
+}
+
+/// One agent's prompts and message templates: the built-in ones unless overridden.
+#[derive(Debug, Clone)]
+pub struct TemplateSet {
+    templates: HashMap<String, PromptTemplate>,
+}
+
+impl Default for TemplateSet {
+    fn default() -> Self {
+        TemplateSet::builtin()
+    }
+}
+
+impl TemplateSet {
+    pub fn builtin() -> Self {
+        let templates = TEMPLATE_VARIABLES
+            .iter()
+            .map(|(name, _)| {
+                let source = builtin_source(name).unwrap_or_default();
+                let template = PromptTemplate::parse(name, &source)
+                    .unwrap_or_else(|e| panic!("built-in template is invalid: {}", e));
+                (name.to_string(), template)
+            })
+            .collect();
+        TemplateSet { templates }
+    }
+
+    /// Replaces the template `name` with `source`.
+    pub fn set(&mut self, name: &str, source: &str) -> Result<(), TemplateError> {
+        let template = PromptTemplate::parse(name, source)?;
+        self.templates.insert(name.to_string(), template);
+        Ok(())
+    }
+
+    pub fn with_template(mut self, name: &str, source: &str) -> Result<Self, TemplateError> {
+        self.set(name, source)?;
+        Ok(self)
+    }
+
+    /// Overrides templates from the files in `dir`, each named after its template, such as
+    /// `planning.md` or `coding_fail.txt`.
+    pub fn load_dir(&mut self, dir: &Path) -> Result<(), TemplateError> {
+        let io_error = |path: &Path, source| TemplateError::Io {
+            path: path.display().to_string(),
+            source,
+        };
+        let mut paths = std::fs::read_dir(dir)
+            .map_err(|e| io_error(dir, e))?
+            .map(|entry| entry.map(|e| e.path()).map_err(|e| io_error(dir, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.sort();
+
+        for path in paths.iter().filter(|p| p.is_file()) {
+            let name = path
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let source = std::fs::read_to_string(path).map_err(|e| io_error(path, e))?;
+            self.set(&name, &source)?;
+        }
+        Ok(())
+    }
+
+    pub fn render(&self, name: &str, vars: &[(&str, &str)]) -> String {
+        self.templates
+            .get(name)
+            .map(|t| t.render(vars))
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_template_has_a_builtin_source() {
+        for (name, _) in TEMPLATE_VARIABLES {
+            assert!(
+                builtin_source(name).is_some(),
+                "no built-in source for '{}'",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn builtin_templates_parse() {
+        for (name, _) in TEMPLATE_VARIABLES {
+            let source = builtin_source(name).unwrap_or_default();
+            if let Err(e) = PromptTemplate::parse(name, &source) {
+                panic!("{}", e);
+            }
+        }
+        TemplateSet::builtin();
+    }
+
+    #[test]
+    fn tool_sections_are_variables() {
+        let rendered = TemplateSet::builtin().render(
+            "tool_router",
+            &[
+                ("tool_signatures", "<tools>echo</tools>"),
+                ("tool_examples", ""),
+                ("tool_call_format", "<tool_call>"),
+            ],
+        );
+        assert!(rendered.contains("<tools>echo</tools>"));
+        assert!(!rendered.contains("{"));
+
+        assert!(matches!(
+            PromptTemplate::parse("tool_router", "{{tool_signatures}} {{tool_signaturs}}"),
+            Err(TemplateError::UnknownVariable { variable, .. }) if variable == "tool_signaturs"
+        ));
+        let error =
+            PromptTemplate::parse("tool_router", "Call a tool. {{tool_call_format}}").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "template 'tool_router' must use '{{tool_signatures}}'"
+        );
+        assert!(PromptTemplate::parse("planning", "Plan it.").is_err());
+        assert!(PromptTemplate::parse("planning", "Plan with {{tool_list}}.").is_ok());
+    }
+
+    #[test]
+    fn unknown_variables_are_rejected() {
+        assert!(matches!(
+            PromptTemplate::parse("coding_retry", "{{error}} {{code}}"),
+            Err(TemplateError::UnknownVariable { variable, .. }) if variable == "code"
+        ));
+        assert!(matches!(
+            PromptTemplate::parse("coding_retry", "{{error"),
+            Err(TemplateError::Unclosed { .. })
+        ));
+    }
+}
@@ -0,0 +1,133 @@
+use crate::tools::ToolSpec;
+use chat_prompts::PromptTemplateType;
+use serde_json::json;
+
+/// How a model family expects the available tools to be listed in its system prompt.
+/// Whatever the family, calls are requested in `<tool_call></tool_call>` tags, which is
+/// what `output_nous_response` parses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ToolSpecSyntax {
+    /// Hermes / Nous function-calling format on ChatML: signatures inside `<tools></tools>`.
+    #[default]
+    Hermes,
+    /// Mistral v3 format: signatures inside `[AVAILABLE_TOOLS] ... [/AVAILABLE_TOOLS]`.
+    Mistral,
+    /// Llama 3 custom-tool format: one "Use the function ..." paragraph per tool.
+    Llama3,
+}
+
+impl From<PromptTemplateType> for ToolSpecSyntax {
+    fn from(template: PromptTemplateType) -> Self {
+        match template {
+            PromptTemplateType::MistralInstruct | PromptTemplateType::MistralLite => {
+                ToolSpecSyntax::Mistral
+            }
+            PromptTemplateType::Llama3Chat => ToolSpecSyntax::Llama3,
+            _ => ToolSpecSyntax::Hermes,
+        }
+    }
+}
+
+fn function_json(spec: &ToolSpec) -> serde_json::Value {
+    json!({
+        "type": "function",
+        "function": {
+            "name": spec.name,
+            "description": spec.description,
+            "parameters": spec.parameters,
+        }
+    })
+}
+
+/// Renders the signatures of `specs` in the syntax the model family was trained on.
+pub fn render_tool_signatures(specs: &[ToolSpec], syntax: ToolSpecSyntax) -> String {
+    match syntax {
+        ToolSpecSyntax::Hermes => {
+            let signatures = specs
+                .iter()
+                .map(|spec| function_json(spec).to_string())
+                .collect::<Vec<String>>()
+                .join("\n");
+            format!(
+                "You are provided with function signatures within <tools></tools> XML tags. Here are the available tools:\n<tools>\n{}\n</tools>",
+                signatures
+            )
+        }
+        ToolSpecSyntax::Mistral => {
+            let signatures = specs.iter().map(function_json).collect::<Vec<_>>();
+            format!(
+                "[AVAILABLE_TOOLS] {}[/AVAILABLE_TOOLS]",
+                serde_json::Value::Array(signatures)
+            )
+        }
+        ToolSpecSyntax::Llama3 => {
+            let signatures = specs
+                .iter()
+                .map(|spec| {
+                    format!(
+                        "Use the function '{}' to: {}\n{}",
+                        spec.name,
+                        spec.description,
+                        function_json(spec)["function"]
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("\n\n");
+            format!(
+                "You have access to the following functions:\n\n{}",
+                signatures
+            )
+        }
+    }
+}
+
+/// Renders one example call per tool that declares example arguments.
+pub fn render_tool_examples(specs: &[ToolSpec]) -> String {
+    let examples = specs
+        .iter()
+        .filter_map(|spec| {
+            let example = spec.example.as_ref()?;
+            Some(format!(
+                "To call {}:\n<tool_call>\n{}\n</tool_call>",
+                spec.name,
+                json!({ "arguments": example, "name": spec.name })
+            ))
+        })
+        .collect::<Vec<String>>();
+
+    if examples.is_empty() {
+        return String::new();
+    }
+    format!(
+        "Examples of tool calls for different scenarios and tools:\n\n{}",
+        examples.join("\n\n")
+    )
+}
+
+pub fn render_tool_call_format() -> String {
+    r#"For each function call return a json object with function name and arguments within <tool_call></tool_call> XML tags as follows:
+<tool_call>
+{"arguments": <args-dict>, "name": "<function-name>"}
+</tool_call>"#
+        .to_string()
+}
+
+/// Renders a plain "name: description" list, for prompts that plan with tools rather than call them.
+pub fn render_tool_list(specs: &[ToolSpec]) -> String {
+    specs
+        .iter()
+        .map(|spec| format!("- {}: {}", spec.name, spec.description))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// The `{{tool_signatures}}`, `{{tool_examples}}`, `{{tool_call_format}}` and `{{tool_list}}`
+/// variables of the prompts that offer tools, rendered from the live tool definitions.
+pub fn tool_sections(specs: &[ToolSpec], syntax: ToolSpecSyntax) -> [(&'static str, String); 4] {
+    [
+        ("tool_signatures", render_tool_signatures(specs, syntax)),
+        ("tool_examples", render_tool_examples(specs)),
+        ("tool_call_format", render_tool_call_format()),
+        ("tool_list", render_tool_list(specs)),
+    ]
+}
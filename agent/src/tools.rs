@@ -0,0 +1,420 @@
+use crate::cassette::Cassette;
+use crate::error::*;
+use crate::feedback::*;
+use crate::immutable_agent::ImmutableAgent;
+use crate::nous_structs::NousToolCall;
+use crate::schema::validate;
+use crate::trace::*;
+use crate::webscraper_hook::*;
+use async_trait::async_trait;
+use futures::future::join_all;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// A capability the model can invoke with a `<tool_call>`.
+#[async_trait(?Send)]
+pub trait Tool {
+    fn name(&self) -> &str;
+
+    fn description(&self) -> &str;
+
+    /// JSON schema of the `arguments` object, in the `{"type": "object", ...}` form. Calls
+    /// are checked against it before `invoke`, which reads them with `parse_args`.
+    fn parameters(&self) -> Value;
+
+    /// Sample `arguments` shown to the model in the generated prompts.
+    fn example_arguments(&self) -> Option<Value> {
+        None
+    }
+
+    async fn invoke(&self, agent: &ImmutableAgent, args: &Value) -> anyhow::Result<String>;
+
+    /// Whether a cassette records this tool's results. Tools made of model calls and
+    /// Python runs, which are recorded on their own, opt out.
+    fn replayable(&self) -> bool {
+        true
+    }
+}
+
+/// The model-facing definition of a tool, used to render the tool-calling prompts.
+#[derive(Debug, Clone, Serialize)]
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value,
+    #[serde(skip)]
+    pub example: Option<Value>,
+}
+
+impl ToolSpec {
+    pub fn of(tool: &dyn Tool) -> Self {
+        ToolSpec {
+            name: tool.name().to_string(),
+            description: tool.description().to_string(),
+            parameters: tool.parameters(),
+            example: tool.example_arguments(),
+        }
+    }
+}
+
+/// Checks `args` against a tool's `parameters`; the error tells the model what to fix.
+pub fn check_args(parameters: &Value, args: &Value) -> anyhow::Result<()> {
+    validate(parameters, args, "the arguments object").map_err(|e| {
+        anyhow::anyhow!(
+            "invalid arguments: {}. The arguments must follow this schema: {}",
+            e,
+            parameters
+        )
+    })
+}
+
+/// Reads a call's arguments into a tool's typed arguments struct.
+pub fn parse_args<T: DeserializeOwned>(args: &Value) -> anyhow::Result<T> {
+    serde_json::from_value(args.clone()).map_err(|e| anyhow::anyhow!("invalid arguments: {}", e))
+}
+
+/// The tools an agent may dispatch to, in registration order.
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    tools: Vec<Arc<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        ToolRegistry { tools: Vec::new() }
+    }
+
+    pub fn builtin() -> Self {
+        ToolRegistry::new()
+            .with_tool(Arc::new(GetWebpageText))
+            .with_tool(Arc::new(SearchWithBing::default()))
+            .with_tool(Arc::new(CodeWithPython))
+    }
+
+    pub fn with_tool(mut self, tool: Arc<dyn Tool>) -> Self {
+        self.register(tool);
+        self
+    }
+
+    /// Adds `tool`, replacing any tool already registered under the same name.
+    pub fn register(&mut self, tool: Arc<dyn Tool>) {
+        match self.tools.iter().position(|t| t.name() == tool.name()) {
+            Some(pos) => self.tools[pos] = tool,
+            None => self.tools.push(tool),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Arc<dyn Tool>> {
+        let pos = self.tools.iter().position(|t| t.name() == name)?;
+        Some(self.tools.remove(pos))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn Tool>> {
+        self.tools.iter().find(|t| t.name() == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<dyn Tool>> {
+        self.tools.iter()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.tools.iter().map(|t| t.name()).collect()
+    }
+
+    pub fn specs(&self) -> Vec<ToolSpec> {
+        self.tools
+            .iter()
+            .map(|t| ToolSpec::of(t.as_ref()))
+            .collect()
+    }
+
+    pub async fn dispatch(
+        &self,
+        agent: &ImmutableAgent,
+        call: &NousToolCall,
+    ) -> AgentResult<String> {
+        agent.usage.record_tool_call();
+        let start = Instant::now();
+        let (span, result) = in_span(None, self.invoke_call(agent, call)).await;
+        agent.tracer.record_span(
+            span,
+            None,
+            TraceEvent::ToolCall {
+                tool: call.name.clone(),
+                arguments: call.arguments.clone(),
+                result: result.as_ref().ok().cloned(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+                duration_ms: elapsed_ms(start),
+            },
+        );
+        result
+    }
+
+    /// Invokes the tool, or answers from the agent's cassette when it is replaying.
+    async fn invoke_call(
+        &self,
+        agent: &ImmutableAgent,
+        call: &NousToolCall,
+    ) -> AgentResult<String> {
+        let tool = self.get(&call.name).ok_or_else(|| AgentError::Tool {
+            tool: call.name.clone(),
+            message: "no such tool is registered".to_string(),
+        })?;
+        let Some(cassette) = agent.cassette.as_ref().filter(|_| tool.replayable()) else {
+            return invoke_tool(tool.as_ref(), agent, call).await;
+        };
+
+        let request = Cassette::tool_request(&call.name, &call.arguments_object());
+        if cassette.is_replaying() {
+            let recorded = cassette
+                .lookup("tool", &request)
+                .and_then(|r| serde_json::from_value::<Result<String, String>>(r).ok())
+                .unwrap_or_else(|| {
+                    Err("the cassette has no recorded result for this call".to_string())
+                });
+            return recorded.map_err(|message| AgentError::Tool {
+                tool: call.name.clone(),
+                message,
+            });
+        }
+
+        let result = invoke_tool(tool.as_ref(), agent, call).await;
+        let recorded = match &result {
+            Ok(output) => Ok(output.clone()),
+            Err(AgentError::Tool { message, .. }) => Err(message.clone()),
+            Err(_) => return result,
+        };
+        cassette.store("tool", request, json!(recorded));
+        result
+    }
+
+    /// Runs all `calls` concurrently; results come back in call order.
+    pub async fn dispatch_all(
+        &self,
+        agent: &ImmutableAgent,
+        calls: &[NousToolCall],
+    ) -> Vec<AgentResult<String>> {
+        join_all(calls.iter().map(|call| self.dispatch(agent, call))).await
+    }
+}
+
+async fn invoke_tool(
+    tool: &dyn Tool,
+    agent: &ImmutableAgent,
+    call: &NousToolCall,
+) -> AgentResult<String> {
+    let args = call.arguments_object();
+    check_args(&tool.parameters(), &args).map_err(|e| AgentError::Tool {
+        tool: call.name.clone(),
+        message: e.to_string(),
+    })?;
+
+    tool.invoke(agent, &args)
+        .await
+        .map_err(|e| match e.downcast::<AgentError>() {
+            Ok(e) if e.ends_run() => e,
+            Ok(e) => AgentError::Tool {
+                tool: call.name.clone(),
+                message: e.to_string(),
+            },
+            Err(e) => AgentError::Tool {
+                tool: call.name.clone(),
+                message: format!("{:#}", e),
+            },
+        })
+}
+
+/// Joins the results of one model response's calls into a single tool result.
+pub fn format_tool_results(calls: &[NousToolCall], results: &[String]) -> String {
+    if let [result] = results {
+        return result.clone();
+    }
+    calls
+        .iter()
+        .zip(results)
+        .enumerate()
+        .map(|(i, (call, result))| format!("Result of call {} ({}):\n{}", i + 1, call.name, result))
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+pub struct GetWebpageText;
+
+#[derive(Deserialize)]
+struct GetWebpageTextArgs {
+    url: String,
+}
+
+#[async_trait(?Send)]
+impl Tool for GetWebpageText {
+    fn name(&self) -> &str {
+        "get_webpage_text"
+    }
+
+    fn description(&self) -> &str {
+        "Fetches all textual content from the specified webpage URL and returns it as plain text. It does not filter out navigation menus, advertisements or other non-essential text, so it works best on a precise URL, ideally one found with search_with_bing first."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "url": {
+                    "type": "string",
+                    "description": "The URL of the website from which to fetch the text content"
+                }
+            },
+            "required": ["url"]
+        })
+    }
+
+    fn example_arguments(&self) -> Option<Value> {
+        Some(json!({ "url": "https://example.com" }))
+    }
+
+    async fn invoke(&self, agent: &ImmutableAgent, args: &Value) -> anyhow::Result<String> {
+        let GetWebpageTextArgs { url } = parse_args(args)?;
+        let url = match agent.review(Checkpoint::BeforeFetch, &url).await? {
+            Feedback::Edit(edited) => edited,
+            Feedback::Reject(comment) => {
+                return Ok(format!(
+                    "The user did not allow fetching {}: {}",
+                    url, comment
+                ))
+            }
+            _ => url,
+        };
+        get_webpage_text(url).await
+    }
+}
+
+pub struct SearchWithBing {
+    /// Number of results to return.
+    pub count: usize,
+}
+
+#[derive(Deserialize)]
+struct SearchWithBingArgs {
+    query: String,
+}
+
+impl Default for SearchWithBing {
+    fn default() -> Self {
+        SearchWithBing { count: 1 }
+    }
+}
+
+#[async_trait(?Send)]
+impl Tool for SearchWithBing {
+    fn name(&self) -> &str {
+        "search_with_bing"
+    }
+
+    fn description(&self) -> &str {
+        "Conducts an internet search using Bing search engine and returns relevant results for the query. Use it to get information you don't have or to cross-check for real-world grounding; promising URLs from the results can then be read with get_webpage_text."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "query": {
+                    "type": "string",
+                    "description": "The search query to be executed on Bing"
+                }
+            },
+            "required": ["query"]
+        })
+    }
+
+    fn example_arguments(&self) -> Option<Value> {
+        Some(json!({ "query": "latest AI research trends" }))
+    }
+
+    async fn invoke(&self, _agent: &ImmutableAgent, args: &Value) -> anyhow::Result<String> {
+        let SearchWithBingArgs { query } = parse_args(args)?;
+        search_with_bing(&query, self.count).await
+    }
+}
+
+pub struct CodeWithPython;
+
+#[derive(Deserialize)]
+struct CodeWithPythonArgs {
+    key_points: String,
+}
+
+#[async_trait(?Send)]
+impl Tool for CodeWithPython {
+    fn name(&self) -> &str {
+        "code_with_python"
+    }
+
+    fn description(&self) -> &str {
+        "Generates and executes clean Python code for various tasks. It handles mathematical computations, data analysis and complex operations through optimized algorithms, providing precise, deterministic outputs."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "key_points": {
+                    "type": "string",
+                    "description": "Key points from input that describes what kind of problem needs to be solved with Python code."
+                }
+            },
+            "required": ["key_points"]
+        })
+    }
+
+    fn example_arguments(&self) -> Option<Value> {
+        Some(json!({
+            "key_points": "Create a Python script that reads a CSV file and plots a graph"
+        }))
+    }
+
+    async fn invoke(&self, agent: &ImmutableAgent, args: &Value) -> anyhow::Result<String> {
+        let CodeWithPythonArgs { key_points } = parse_args(args)?;
+        let outcome = agent.code_with_python(&key_points).await?;
+
+        Ok(outcome.to_tool_result())
+    }
+
+    fn replayable(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_args_tells_the_model_what_to_fix() {
+        let parameters = GetWebpageText.parameters();
+        assert!(check_args(&parameters, &json!({ "url": "https://example.com" })).is_ok());
+        assert_eq!(
+            check_args(&parameters, &json!({ "link": "https://example.com" }))
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "invalid arguments: the arguments object is missing `url`. \
+                 The arguments must follow this schema: {}",
+                parameters
+            )
+        );
+        assert_eq!(
+            check_args(&parameters, &json!({ "url": 42 }))
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "invalid arguments: `url` should be string, found 42. \
+                 The arguments must follow this schema: {}",
+                parameters
+            )
+        );
+    }
+}
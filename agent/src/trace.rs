@@ -0,0 +1,254 @@
+use crate::chat_backend::{BackendError, ChatBackend};
+use async_trait::async_trait;
+use endpoints::{
+    chat::{ChatCompletionObject, ChatCompletionRequest},
+    common::Usage,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// One thing the agent did, as written to the trace.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TraceEvent {
+    BackendRequest {
+        model: Option<String>,
+        messages: Value,
+        /// Every other field of the request: temperature, top_p, penalties and so on.
+        sampling: Value,
+        latency_ms: u64,
+        usage: Option<Usage>,
+        reply: Option<String>,
+        error: Option<String>,
+    },
+    ToolCall {
+        tool: String,
+        arguments: Value,
+        result: Option<String>,
+        error: Option<String>,
+        duration_ms: u64,
+    },
+    PythonExecution {
+        code: String,
+        stdout: Option<String>,
+        error: Option<String>,
+        duration_ms: u64,
+    },
+    /// A plan step, recorded when it finishes; the calls it made are its children.
+    Step {
+        description: String,
+        output: Option<String>,
+        error: Option<String>,
+        duration_ms: u64,
+    },
+    /// What the planner or gatekeeper decided: a plan, a revision, whether the goal is met.
+    PlannerDecision { decision: String, detail: Value },
+}
+
+/// A trace line: the event plus where in the run it happened.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TraceRecord {
+    pub id: u64,
+    /// The step or tool call the event happened inside of.
+    pub parent_id: Option<u64>,
+    pub run_id: Option<String>,
+    pub step_id: Option<String>,
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub event: TraceEvent,
+}
+
+pub trait TraceSink: Send + Sync {
+    fn write(&self, record: &TraceRecord);
+}
+
+/// Appends one JSON object per line to a file.
+pub struct JsonlTraceSink {
+    file: Mutex<File>,
+}
+
+impl JsonlTraceSink {
+    pub fn new(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonlTraceSink {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl TraceSink for JsonlTraceSink {
+    fn write(&self, record: &TraceRecord) {
+        let Ok(line) = serde_json::to_string(record) else {
+            return;
+        };
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{}", line) {
+            println!("[WARN] could not write trace: {}", e);
+        }
+    }
+}
+
+/// Where in a run the current future is; set with `in_scope` and picked up by every
+/// event recorded inside it.
+#[derive(Debug, Clone, Default)]
+pub struct TraceScope {
+    pub run_id: Option<String>,
+    pub step_id: Option<String>,
+    pub span_id: Option<u64>,
+}
+
+tokio::task_local! {
+    static SCOPE: TraceScope;
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+pub fn next_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub fn current_scope() -> TraceScope {
+    SCOPE.try_with(|scope| scope.clone()).unwrap_or_default()
+}
+
+/// Runs `fut` with `scope` as its trace scope. Concurrent futures each keep their own.
+pub async fn in_scope<F: Future>(scope: TraceScope, fut: F) -> F::Output {
+    SCOPE.scope(scope, fut).await
+}
+
+/// Runs `fut` as a child span of the current scope, returning the span's id along with
+/// its output so the event describing it can be recorded under that id.
+pub async fn in_span<F: Future>(step_id: Option<&str>, fut: F) -> (u64, F::Output) {
+    let id = next_id();
+    let mut scope = current_scope();
+    scope.span_id = Some(id);
+    if let Some(step_id) = step_id {
+        scope.step_id = Some(step_id.to_string());
+    }
+    (id, in_scope(scope, fut).await)
+}
+
+pub fn elapsed_ms(start: Instant) -> u64 {
+    start.elapsed().as_millis() as u64
+}
+
+/// Hands events to a sink, or drops them when tracing is off.
+#[derive(Clone, Default)]
+pub struct Tracer {
+    sink: Option<Arc<dyn TraceSink>>,
+}
+
+impl Tracer {
+    pub fn new(sink: Arc<dyn TraceSink>) -> Self {
+        Tracer { sink: Some(sink) }
+    }
+
+    pub fn jsonl(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Tracer::new(Arc::new(JsonlTraceSink::new(path)?)))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    pub fn record(&self, event: TraceEvent) {
+        let scope = current_scope();
+        self.write(next_id(), scope.span_id, scope, event);
+    }
+
+    /// Records the event describing the span `id` started by `in_span`, under the span
+    /// enclosing it; `step_id` is set when the span is a plan step.
+    pub fn record_span(&self, id: u64, step_id: Option<&str>, event: TraceEvent) {
+        let mut scope = current_scope();
+        if let Some(step_id) = step_id {
+            scope.step_id = Some(step_id.to_string());
+        }
+        self.write(id, scope.span_id, scope, event);
+    }
+
+    fn write(&self, id: u64, parent_id: Option<u64>, scope: TraceScope, event: TraceEvent) {
+        let Some(sink) = &self.sink else {
+            return;
+        };
+        sink.write(&TraceRecord {
+            id,
+            parent_id,
+            run_id: scope.run_id,
+            step_id: scope.step_id,
+            timestamp: chrono::Local::now().to_rfc3339(),
+            event,
+        });
+    }
+
+    pub fn planner_decision(&self, decision: &str, detail: Value) {
+        self.record(TraceEvent::PlannerDecision {
+            decision: decision.to_string(),
+            detail,
+        });
+    }
+}
+
+/// Records every request `inner` answers.
+pub struct TracedBackend {
+    inner: Arc<dyn ChatBackend>,
+    tracer: Tracer,
+}
+
+impl TracedBackend {
+    pub fn new(inner: Arc<dyn ChatBackend>, tracer: Tracer) -> Self {
+        TracedBackend { inner, tracer }
+    }
+}
+
+#[async_trait(?Send)]
+impl ChatBackend for TracedBackend {
+    fn supports_json_mode(&self) -> bool {
+        self.inner.supports_json_mode()
+    }
+
+    async fn chat(
+        &self,
+        chat_request: &mut ChatCompletionRequest,
+    ) -> Result<ChatCompletionObject, BackendError> {
+        let mut sampling = serde_json::to_value(&*chat_request).unwrap_or_default();
+        let messages = sampling
+            .as_object_mut()
+            .and_then(|fields| fields.remove("messages"))
+            .unwrap_or_default();
+        let model = chat_request.model.clone();
+
+        let start = Instant::now();
+        let res = self.inner.chat(chat_request).await;
+        let latency_ms = elapsed_ms(start);
+
+        let (usage, reply, error) = match &res {
+            Ok(obj) => (
+                Some(Usage {
+                    prompt_tokens: obj.usage.prompt_tokens,
+                    completion_tokens: obj.usage.completion_tokens,
+                    total_tokens: obj.usage.total_tokens,
+                }),
+                obj.choices.first().map(|c| c.message.content.clone()),
+                None,
+            ),
+            Err(e) => (None, None, Some(e.to_string())),
+        };
+        self.tracer.record(TraceEvent::BackendRequest {
+            model,
+            messages,
+            sampling,
+            latency_ms,
+            usage,
+            reply,
+            error,
+        });
+        res
+    }
+}
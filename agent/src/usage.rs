@@ -0,0 +1,165 @@
+use crate::error::*;
+use crate::trace::current_scope;
+use endpoints::common::Usage;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Tokens spent over some number of model calls.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct TokenUsage {
+    pub calls: usize,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl TokenUsage {
+    fn add(&mut self, usage: &Usage) {
+        self.calls += 1;
+        self.prompt_tokens += usage.prompt_tokens;
+        self.completion_tokens += usage.completion_tokens;
+        self.total_tokens += usage.total_tokens;
+    }
+}
+
+impl std::fmt::Display for TokenUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} calls, {} tokens ({} prompt + {} completion)",
+            self.calls, self.total_tokens, self.prompt_tokens, self.completion_tokens
+        )
+    }
+}
+
+/// One model call: who made it, in which plan step, and what it cost.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CallUsage {
+    pub role: String,
+    pub step_id: Option<String>,
+    pub usage: TokenUsage,
+}
+
+/// Every call of a run, with totals per role and per plan step.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct UsageReport {
+    pub calls: Vec<CallUsage>,
+    pub total: TokenUsage,
+    pub by_role: BTreeMap<String, TokenUsage>,
+    pub by_step: BTreeMap<String, TokenUsage>,
+    /// Tool calls dispatched, whether or not they succeeded.
+    pub tool_calls: usize,
+}
+
+impl UsageReport {
+    fn add(&mut self, role: &str, step_id: Option<String>, usage: &Usage) {
+        let mut call = TokenUsage::default();
+        call.add(usage);
+        self.total.add(usage);
+        self.by_role.entry(role.to_string()).or_default().add(usage);
+        if let Some(step_id) = &step_id {
+            self.by_step.entry(step_id.clone()).or_default().add(usage);
+        }
+        self.calls.push(CallUsage {
+            role: role.to_string(),
+            step_id,
+            usage: call,
+        });
+    }
+
+    /// One line with the run's totals.
+    pub fn summary(&self) -> String {
+        format!("Usage: {}, {} tool calls", self.total, self.tool_calls)
+    }
+
+    /// The totals, then a line per role and per step.
+    pub fn details(&self) -> String {
+        let mut lines = vec![self.summary()];
+        lines.extend(
+            self.by_role
+                .iter()
+                .map(|(role, usage)| format!("  {:<12} {}", role, usage)),
+        );
+        if !self.by_step.is_empty() {
+            lines.push("Per step:".to_string());
+            lines.extend(
+                self.by_step
+                    .iter()
+                    .map(|(step, usage)| format!("  {:<12} {}", step, usage)),
+            );
+        }
+        lines.join("\n")
+    }
+}
+
+/// Caps on what a single run may spend; `None` means no cap.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Budget {
+    pub max_tokens: Option<u64>,
+    pub max_calls: Option<usize>,
+}
+
+/// Adds up the usage of every model call made by the agents sharing it, and enforces
+/// the budget.
+#[derive(Debug, Default)]
+pub struct UsageMeter {
+    budget: Budget,
+    report: Mutex<UsageReport>,
+}
+
+impl UsageMeter {
+    pub fn new(budget: Budget) -> Self {
+        UsageMeter {
+            budget,
+            report: Mutex::new(UsageReport::default()),
+        }
+    }
+
+    pub fn budget(&self) -> Budget {
+        self.budget
+    }
+
+    pub fn report(&self) -> UsageReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    /// Starts counting from zero, as at the beginning of a run.
+    pub fn reset(&self) {
+        *self.report.lock().unwrap() = UsageReport::default();
+    }
+
+    /// Errors once the budget is used up, so no further call is made.
+    pub fn check(&self) -> AgentResult<()> {
+        let total = self.report.lock().unwrap().total;
+        if let Some(max_calls) = self.budget.max_calls {
+            if total.calls >= max_calls {
+                return Err(AgentError::BudgetExhausted {
+                    what: "call".to_string(),
+                    limit: max_calls as u64,
+                });
+            }
+        }
+        if let Some(max_tokens) = self.budget.max_tokens {
+            if total.total_tokens >= max_tokens {
+                return Err(AgentError::BudgetExhausted {
+                    what: "token".to_string(),
+                    limit: max_tokens,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn record_tool_call(&self) {
+        self.report.lock().unwrap().tool_calls += 1;
+    }
+
+    /// Counts a call made as `role`, under the plan step it ran in, if any.
+    pub fn record(&self, role: &str, usage: &Usage) {
+        self.report
+            .lock()
+            .unwrap()
+            .add(role, current_scope().step_id, usage);
+    }
+}
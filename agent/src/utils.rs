@@ -1,73 +1,291 @@
-use regex::Regex;
-
-pub fn parse_next_move_and_(
-    input: &str,
-    next_marker: Option<&str>,
-) -> (bool, Option<String>, Vec<String>) {
-    let json_regex = Regex::new(r"\{[^}]*\}").unwrap();
-    let json_str = json_regex
-        .captures(input)
-        .and_then(|cap| cap.get(0))
-        .map_or(String::new(), |m| m.as_str().to_string());
-
-    let continue_or_terminate_regex =
-        Regex::new(r#""continue_or_terminate":\s*"([^"]*)""#).unwrap();
-    let continue_or_terminate = continue_or_terminate_regex
-        .captures(&json_str)
-        .and_then(|cap| cap.get(1))
-        .map_or(String::new(), |m| m.as_str().to_string());
-
-    let next_move = match next_marker {
-        Some(marker) => {
-            let next_marker_regex = Regex::new(&format!(r#""{}":\s*"([^"]*)""#, marker)).unwrap();
-            Some(
-                next_marker_regex
-                    .captures(&json_str)
-                    .and_then(|cap| cap.get(1))
-                    .map_or(String::new(), |m| m.as_str().to_string()),
-            )
+use crate::json_extract::*;
+use crate::planning::*;
+use crate::schema::*;
+use serde::de::{DeserializeOwned, Error};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Map, Value};
+
+/// The planner's reply to `NEXT_STEP_PLANNING_PROMPT` and the replanning prompt.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PlanningReply {
+    pub my_goal: Option<String>,
+    pub my_thought_process: Vec<String>,
+    /// Read from `steps_to_take`, given either as step objects or as plain strings, which
+    /// are taken to run one after another.
+    pub plan: Plan,
+}
+
+/// The gatekeeper's reply to `IS_TERMINATION_PROMPT`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TerminationReply {
+    /// `continue_or_terminate` is "TERMINATE".
+    pub terminate: bool,
+    pub key_points: Vec<String>,
+}
+
+impl<'de> Deserialize<'de> for PlanningReply {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        // a bare list is taken as the steps themselves
+        if value.is_array() {
+            return Ok(PlanningReply {
+                plan: plan_from(value).map_err(D::Error::custom)?,
+                ..Default::default()
+            });
         }
-        None => None,
-    };
-
-    let key_points_array_regex = Regex::new(r#""key_points":\s*\[(.*?)\]"#).unwrap();
-
-    let key_points_array = key_points_array_regex
-        .captures(&json_str)
-        .and_then(|cap| cap.get(1))
-        .map_or(String::new(), |m| m.as_str().to_string());
-
-    let key_points: Vec<String> = if !key_points_array.is_empty() {
-        key_points_array
-            .split(',')
-            .map(|s| s.trim().trim_matches('"').to_string())
-            .collect()
-    } else {
-        vec![]
-    };
-
-    (&continue_or_terminate == "TERMINATE", next_move, key_points)
+        let mut fields = Fields::of(value).map_err(D::Error::custom)?;
+
+        Ok(PlanningReply {
+            my_goal: fields.optional("my_goal").map_err(D::Error::custom)?,
+            my_thought_process: fields
+                .text_list("my_thought_process")
+                .map_err(D::Error::custom)?,
+            plan: plan_from(fields.required("steps_to_take").map_err(D::Error::custom)?)
+                .map_err(D::Error::custom)?,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for TerminationReply {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut fields = Fields::of(Value::deserialize(deserializer)?).map_err(D::Error::custom)?;
+
+        Ok(TerminationReply {
+            terminate: fields
+                .choice("continue_or_terminate", "TERMINATE", "CONTINUE")
+                .map_err(D::Error::custom)?,
+            key_points: fields.text_list("key_points").map_err(D::Error::custom)?,
+        })
+    }
+}
+
+impl StructuredReply for PlanningReply {
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "my_goal": { "type": "string" },
+                "my_thought_process": text_list_schema(),
+                "steps_to_take": {
+                    "type": "array",
+                    "items": {
+                        "anyOf": [
+                            { "type": "string" },
+                            {
+                                "type": "object",
+                                "properties": {
+                                    "id": { "type": "string" },
+                                    "description": { "type": "string" },
+                                    "depends_on": { "type": "array", "items": { "type": "string" } },
+                                    "tool": { "type": "string" }
+                                },
+                                "required": ["id", "description"]
+                            }
+                        ]
+                    }
+                }
+            },
+            "required": ["steps_to_take"]
+        })
+    }
+}
+
+impl StructuredReply for TerminationReply {
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "continue_or_terminate": { "type": "string", "description": "TERMINATE or CONTINUE" },
+                "key_points": text_list_schema()
+            },
+            "required": ["continue_or_terminate"]
+        })
+    }
+}
+
+/// A list of strings, or a single one; see `Fields::text_list`.
+fn text_list_schema() -> Value {
+    json!({ "type": ["array", "string"], "items": { "type": "string" } })
+}
+
+/// Reads a model reply as `T`, checking its JSON against `T::schema()` first; the error is
+/// worded to be sent back to the model.
+pub fn check_reply<T: StructuredReply>(input: &str) -> Result<T, String> {
+    let value = extract_json(input).ok_or_else(|| "the reply contains no JSON".to_string())?;
+    validate(&T::schema(), &value, "the reply")?;
+    serde_json::from_value::<T>(value).map_err(|e| e.to_string())
+}
+
+/// Reads the JSON a model reply carries as `T`; the error names the missing or malformed
+/// field, or says the reply had no JSON at all.
+pub fn parse_reply<T: DeserializeOwned>(input: &str) -> Result<T, String> {
+    let value = extract_json(input).ok_or_else(|| "the reply contains no JSON".to_string())?;
+    serde_json::from_value::<T>(value).map_err(|e| e.to_string())
+}
+
+fn plan_from(steps: Value) -> Result<Plan, String> {
+    if let Ok(steps) = serde_json::from_value::<Vec<PlanStep>>(steps.clone()) {
+        return Ok(Plan::new(steps));
+    }
+    match serde_json::from_value::<Vec<String>>(steps) {
+        Ok(descriptions) => Ok(Plan::sequential(descriptions)),
+        Err(_) => Err(
+            "malformed field `steps_to_take`: expected a list of steps, each an object with \
+             `id` and `description` or a plain string"
+                .to_string(),
+        ),
+    }
 }
 
-pub fn parse_planning_steps(input: &str) -> Vec<String> {
-    let steps_regex = Regex::new(r#""steps_to_take":\s*(\[[^\]]*\])"#).unwrap();
-    let steps_str = steps_regex
-        .captures(input)
-        .and_then(|cap| cap.get(1))
-        .map_or(String::new(), |m| m.as_str().to_string());
+/// The fields of a reply object, taken out one at a time.
+struct Fields(Map<String, Value>);
 
-    if steps_str.is_empty() {
-        eprintln!("Failed to extract 'steps_to_take' from input.");
-        return vec![];
+impl Fields {
+    fn of(value: Value) -> Result<Self, String> {
+        match value {
+            Value::Object(map) => Ok(Fields(map)),
+            other => Err(format!("expected a JSON object, found {}", other)),
+        }
     }
 
-    let parsed_steps: Vec<String> = match serde_json::from_str(&steps_str) {
-        Ok(val) => val,
-        Err(_) => {
-            eprintln!("Failed to parse extracted 'steps_to_take' as JSON.");
-            return vec![];
+    fn required(&mut self, name: &str) -> Result<Value, String> {
+        match self.0.remove(name) {
+            Some(Value::Null) | None => Err(format!("missing field `{}`", name)),
+            Some(value) => Ok(value),
         }
-    };
+    }
+
+    fn optional<T: DeserializeOwned>(&mut self, name: &str) -> Result<Option<T>, String> {
+        match self.0.remove(name) {
+            Some(Value::Null) | None => Ok(None),
+            Some(value) => serde_json::from_value(value)
+                .map(Some)
+                .map_err(|e| format!("malformed field `{}`: {}", name, e)),
+        }
+    }
 
-    parsed_steps
+    /// A list of strings; a single string is taken as a one-item list and other values
+    /// are kept as their JSON text.
+    fn text_list(&mut self, name: &str) -> Result<Vec<String>, String> {
+        let text = |value: Value| match value {
+            Value::String(s) => s.trim().to_string(),
+            other => other.to_string(),
+        };
+        match self.0.remove(name) {
+            Some(Value::Null) | None => Ok(vec![]),
+            Some(Value::Array(items)) => Ok(items.into_iter().map(text).collect()),
+            Some(Value::String(s)) if s.trim().is_empty() => Ok(vec![]),
+            Some(Value::String(s)) => Ok(vec![s.trim().to_string()]),
+            Some(other) => Err(format!(
+                "malformed field `{}`: expected a list of strings, found {}",
+                name, other
+            )),
+        }
+    }
+
+    /// Whether the field says `yes` rather than `no`, compared case-insensitively.
+    fn choice(&mut self, name: &str, yes: &str, no: &str) -> Result<bool, String> {
+        let value = self.required(name)?;
+        match value.as_str().map(str::trim) {
+            Some(s) if s.eq_ignore_ascii_case(yes) => Ok(true),
+            Some(s) if s.eq_ignore_ascii_case(no) => Ok(false),
+            _ => Err(format!(
+                "malformed field `{}`: expected \"{}\" or \"{}\", found {}",
+                name, yes, no, value
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_fields_are_named_in_errors() {
+        let planning = [
+            (r#"{"my_goal": "x"}"#, "missing field `steps_to_take`"),
+            (
+                r#"{"my_goal": 5, "steps_to_take": []}"#,
+                "malformed field `my_goal`: invalid type: integer `5`, expected a string",
+            ),
+            (
+                r#"{"my_thought_process": 5, "steps_to_take": []}"#,
+                "malformed field `my_thought_process`: expected a list of strings, found 5",
+            ),
+            (
+                r#"{"steps_to_take": 3}"#,
+                "malformed field `steps_to_take`: expected a list of steps, each an object with \
+                 `id` and `description` or a plain string",
+            ),
+            ("no JSON here", "the reply contains no JSON"),
+        ];
+        for (reply, expected) in planning {
+            assert_eq!(parse_reply::<PlanningReply>(reply).unwrap_err(), expected);
+        }
+
+        let termination = [
+            (
+                r#"{"key_points": []}"#,
+                "missing field `continue_or_terminate`",
+            ),
+            (
+                r#"{"continue_or_terminate": "MAYBE"}"#,
+                r#"malformed field `continue_or_terminate`: expected "TERMINATE" or "CONTINUE", found "MAYBE""#,
+            ),
+            (
+                r#"{"continue_or_terminate": true}"#,
+                r#"malformed field `continue_or_terminate`: expected "TERMINATE" or "CONTINUE", found true"#,
+            ),
+        ];
+        for (reply, expected) in termination {
+            assert_eq!(
+                parse_reply::<TerminationReply>(reply).unwrap_err(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn replies_are_read_leniently() {
+        let reply = parse_reply::<TerminationReply>(
+            r#"{"continue_or_terminate": " terminate ", "key_points": "Paris"}"#,
+        )
+        .unwrap();
+        assert!(reply.terminate);
+        assert_eq!(reply.key_points, ["Paris"]);
+
+        let reply = parse_reply::<PlanningReply>(r#"["look it up", "answer"]"#).unwrap();
+        assert_eq!(reply.plan.steps.len(), 2);
+        assert_eq!(reply.plan.steps[1].depends_on, ["s1"]);
+    }
+
+    #[test]
+    fn check_reply_says_what_to_fix() {
+        let cases = [
+            (
+                r#"{"key_points": []}"#,
+                "the reply is missing `continue_or_terminate`",
+            ),
+            (
+                r#"{"continue_or_terminate": 1}"#,
+                "`continue_or_terminate` should be string, found 1",
+            ),
+            ("[1, 2]", "the reply should be object, found a list"),
+        ];
+        for (reply, expected) in cases {
+            assert_eq!(
+                check_reply::<TerminationReply>(reply).unwrap_err(),
+                expected
+            );
+        }
+        assert_eq!(
+            check_reply::<PlanningReply>(r#"{"steps_to_take": [{"id": "s1"}]}"#).unwrap_err(),
+            "`steps_to_take[0]` should be string, found an object; \
+             or `steps_to_take[0]` is missing `steps_to_take[0].description`"
+        );
+        assert!(
+            check_reply::<TerminationReply>(r#"{"continue_or_terminate": "CONTINUE"}"#).is_ok()
+        );
+    }
 }
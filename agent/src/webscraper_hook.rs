@@ -11,7 +11,7 @@ pub async fn get_webpage_text(url: String) -> anyhow::Result<String> {
     Ok(res)
 }
 
-pub async fn search_with_bing(query: &str) -> anyhow::Result<String> {
+pub async fn search_with_bing(query: &str, count: usize) -> anyhow::Result<String> {
     #[allow(unused)]
     #[allow(non_snake_case)]
     #[derive(Debug, Clone, Deserialize)]
@@ -83,7 +83,7 @@ pub async fn search_with_bing(query: &str) -> anyhow::Result<String> {
     let encoded_query = urlencoding::encode(query);
 
     let url_str =
-        format!("https://api.bing.microsoft.com/v7.0/search?count=1&q={}&responseFilter=Webpages&setLang=en", encoded_query);
+        format!("https://api.bing.microsoft.com/v7.0/search?count={}&q={}&responseFilter=Webpages&setLang=en", count, encoded_query);
     let mut headers = HeaderMap::new();
     let bing_key = std::env::var("BING_API_KEY").unwrap_or("bing_api_key not found".to_string());
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
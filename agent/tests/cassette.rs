@@ -0,0 +1,64 @@
+//! Recording a run to a cassette and replaying it without a model.
+
+use llama_agent::cassette::*;
+use llama_agent::chat_backend::*;
+use llama_agent::immutable_agent::*;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const TASK: &str = "What is the capital of France?";
+
+const PLAN: &str = r#"```json
+{"my_goal": "answer the question", "my_thought_process": ["look it up"], "steps_to_take": [{"id": "s1", "description": "Find the capital of France"}]}
+```"#;
+
+fn cassette_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("llama-agent-{}-{}.jsonl", name, std::process::id()))
+}
+
+fn agent(backend: Arc<dyn ChatBackend>, cassette: Cassette) -> ImmutableAgent {
+    ImmutableAgent::new("test", "You are a helpful assistant.")
+        .with_backend(backend)
+        .with_cassette(Arc::new(cassette))
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn a_recorded_run_replays_without_the_model() {
+    let path = cassette_path("replay");
+    let backend = Arc::new(ScriptedBackend::new([PLAN, "Paris"]));
+    let recording = agent(backend.clone(), Cassette::record(&path).unwrap());
+    let recorded = recording.run(TASK, RunMode::PlanAndExecute).await.unwrap();
+    assert_eq!(backend.requests().len(), 2);
+
+    let entries = std::fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<CassetteEntry>(line).unwrap())
+        .collect::<Vec<CassetteEntry>>();
+    assert_eq!(entries.len(), 2);
+    for entry in &entries {
+        assert_eq!(entry.kind, "chat");
+        assert_eq!(entry.key, Cassette::key("chat", &entry.request));
+        assert!(entry.key.starts_with("chat-") && entry.key.len() == "chat-".len() + 16);
+    }
+    assert_ne!(entries[0].key, entries[1].key);
+
+    let replaying = agent(Arc::new(NoBackend), Cassette::replay(&path).unwrap());
+    let replayed = replaying.run(TASK, RunMode::PlanAndExecute).await.unwrap();
+    assert_eq!(replayed, recorded);
+    assert_eq!(replayed, "Paris");
+
+    let missing = agent(Arc::new(NoBackend), Cassette::replay(&path).unwrap());
+    let error = missing
+        .run("What is the capital of Italy?", RunMode::PlanAndExecute)
+        .await
+        .unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("the cassette has no recorded reply for request chat-"),
+        "{}",
+        error
+    );
+    std::fs::remove_file(&path).unwrap();
+}
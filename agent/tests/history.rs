@@ -0,0 +1,126 @@
+//! What each role remembers of its own earlier requests.
+
+use llama_agent::chat_backend::*;
+use llama_agent::conversation::*;
+use llama_agent::immutable_agent::*;
+use std::sync::Arc;
+
+const TASK: &str = "What is the capital of France?";
+
+const PLAN: &str = r#"```json
+{"my_goal": "answer the question", "steps_to_take": [{"id": "s1", "description": "Find the capital of France"}]}
+```"#;
+
+const TERMINATE: &str = r#"{"continue_or_terminate": "TERMINATE", "key_points": ["Paris"]}"#;
+
+fn agent(backend: &Arc<ScriptedBackend>) -> ImmutableAgent {
+    ImmutableAgent::new("test", "You are a helpful assistant.")
+        .with_backend(backend.clone())
+        .with_replanning(1)
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn only_roles_with_history_keep_a_thread() {
+    let backend = Arc::new(ScriptedBackend::new([PLAN, "Paris", TERMINATE]));
+    let agent = agent(&backend);
+
+    agent.run(TASK, RunMode::PlanAndExecute).await.unwrap();
+
+    let planner = agent.thread(AgentRole::Planner).unwrap();
+    let history = planner
+        .history()
+        .iter()
+        .map(message_text)
+        .collect::<Vec<String>>();
+    assert_eq!(history, [TASK, PLAN]);
+    assert!(agent.thread(AgentRole::ToolRouter).is_none());
+    assert!(agent.thread(AgentRole::Gatekeeper).is_none());
+    // the router and the gatekeeper see only their system prompt and input
+    assert_eq!(backend.requests()[1].len(), 2);
+    assert_eq!(backend.requests()[2].len(), 2);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn every_run_starts_with_fresh_threads() {
+    let backend = Arc::new(ScriptedBackend::new([
+        PLAN, "Paris", TERMINATE, PLAN, "Paris", TERMINATE,
+    ]));
+    let agent = agent(&backend);
+
+    agent.run(TASK, RunMode::PlanAndExecute).await.unwrap();
+    agent.run(TASK, RunMode::PlanAndExecute).await.unwrap();
+
+    let second_plan = &backend.requests()[3];
+    assert_eq!(second_plan.len(), 2);
+    assert_eq!(message_text(&second_plan[1]), TASK);
+    assert_eq!(agent.thread(AgentRole::Planner).unwrap().history().len(), 2);
+}
+
+/// A thread of `turns` exchanges of about 250 tokens a message.
+fn long_thread(turns: usize) -> Conversation {
+    let mut conversation = Conversation::for_role(AgentRole::Reasoner, "Think, then act.");
+    for n in 1..=turns {
+        conversation.record_turn(
+            &format!("question {} {}", n, "q".repeat(1000)),
+            &format!("answer {} {}", n, "a".repeat(1000)),
+        );
+    }
+    conversation
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn a_full_window_compresses_all_but_the_last_turns() {
+    let backend = Arc::new(ScriptedBackend::new([
+        "They asked and got answers 1 and 2.",
+    ]));
+    // room for 1536 prompt tokens; compression starts at 1382
+    let agent = agent(&backend).with_context_window(2048, 512);
+
+    let mut short = long_thread(1);
+    assert_eq!(
+        agent.compress_if_needed(&mut short, "next").await.unwrap(),
+        None
+    );
+    assert_eq!(short.history().len(), 2);
+
+    let mut conversation = long_thread(4);
+    let summary = agent
+        .compress_if_needed(&mut conversation, "next")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        summary.as_deref(),
+        Some("They asked and got answers 1 and 2.")
+    );
+    let kept = conversation
+        .history()
+        .iter()
+        .map(|m| message_text(m)[..8].to_string())
+        .collect::<Vec<String>>();
+    assert_eq!(kept, ["question", "answer 3", "question", "answer 4"]);
+    assert!(message_text(&conversation.history()[0]).starts_with("question 3"));
+
+    let requests = backend.requests();
+    assert_eq!(requests.len(), 1);
+    let summarized = message_text(&requests[0][1]);
+    assert!(summarized.contains("question 1") && summarized.contains("answer 2"));
+    assert!(!summarized.contains("question 3"));
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn the_last_turns_are_never_compressed() {
+    let backend = Arc::new(ScriptedBackend::new(Vec::<String>::new()));
+    let agent = agent(&backend).with_context_window(1024, 512);
+
+    let mut conversation = long_thread(2);
+    assert_eq!(
+        agent
+            .compress_if_needed(&mut conversation, "next")
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(conversation.history().len(), 4);
+    assert!(backend.requests().is_empty());
+}
@@ -0,0 +1,176 @@
+//! Whole runs against scripted model replies: planning, the steps, and the answer.
+
+use llama_agent::chat_backend::*;
+use llama_agent::conversation::*;
+use llama_agent::feedback::*;
+use llama_agent::immutable_agent::*;
+use llama_agent::run_state::*;
+use llama_agent::usage::*;
+use std::sync::Arc;
+
+const TASK: &str = "What is the capital of France?";
+
+fn agent(backend: &Arc<ScriptedBackend>) -> ImmutableAgent {
+    ImmutableAgent::new("test", "You are a helpful assistant.").with_backend(backend.clone())
+}
+
+fn plan(steps: &str) -> String {
+    format!(
+        "```json\n{{\"my_goal\": \"answer the question\", \"my_thought_process\": [\"look it up, then answer\"], \"steps_to_take\": {}}}\n```",
+        steps
+    )
+}
+
+const TWO_STEPS: &str = r#"[
+    {"id": "s1", "description": "Find the capital of France", "tool": "use_intrinsic_knowledge"},
+    {"id": "s2", "description": "Write the answer", "depends_on": ["s1"]}
+]"#;
+
+/// The text of the last message of the `n`th request the backend received.
+fn last_input(backend: &ScriptedBackend, n: usize) -> String {
+    message_text(backend.requests()[n].last().unwrap())
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn plan_steps_answer() {
+    let backend = Arc::new(ScriptedBackend::new([
+        plan(TWO_STEPS),
+        r#"<tool_call>{"name": "use_intrinsic_knowledge", "arguments": {"task": "Find the capital of France"}}</tool_call>"#.to_string(),
+        "Paris".to_string(),
+        "The capital of France is Paris.".to_string(),
+    ]));
+    let feedback = Arc::new(ScriptedFeedback::default());
+    let agent = agent(&backend).with_feedback(
+        feedback.clone(),
+        &[Checkpoint::AfterPlan, Checkpoint::BeforeAnswer],
+    );
+
+    let answer = agent.run(TASK, RunMode::PlanAndExecute).await.unwrap();
+
+    assert_eq!(answer, "The capital of France is Paris.");
+    assert_eq!(backend.requests().len(), 4);
+    assert!(last_input(&backend, 2).starts_with("Find the capital of France"));
+    assert!(last_input(&backend, 3).contains("[s1] Paris"));
+    let checkpoints = feedback
+        .reviewed()
+        .into_iter()
+        .map(|(checkpoint, _)| checkpoint)
+        .collect::<Vec<Checkpoint>>();
+    assert_eq!(
+        checkpoints,
+        vec![Checkpoint::AfterPlan, Checkpoint::BeforeAnswer]
+    );
+    let run = agent.current_run().unwrap();
+    assert_eq!(run.completed, vec!["s1", "s2"]);
+    assert!(matches!(run.phase, RunPhase::Done { .. }));
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn a_dependent_step_answered_directly_sees_its_inputs() {
+    let backend = Arc::new(ScriptedBackend::new([
+        plan(
+            r#"[
+                {"id": "s1", "description": "Find the year Ada Lovelace was born"},
+                {"id": "s2", "description": "Compute her age at her death in 1852", "depends_on": ["s1"], "tool": "use_intrinsic_knowledge"}
+            ]"#,
+        ),
+        "1815".to_string(),
+        r#"<tool_call>{"name": "use_intrinsic_knowledge", "arguments": {"task": "Compute her age"}}</tool_call>"#.to_string(),
+        "She was 36 or 37.".to_string(),
+    ]));
+    let agent = agent(&backend);
+
+    let answer = agent.run(TASK, RunMode::PlanAndExecute).await.unwrap();
+
+    assert_eq!(answer, "She was 36 or 37.");
+    let direct = last_input(&backend, 3);
+    assert!(direct.starts_with("Compute her age at her death in 1852"));
+    assert!(direct.contains("[s1] 1815"));
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn a_malformed_plan_is_asked_for_again() {
+    let malformed = r#"```json
+{"my_goal": "answer the question", "steps": ["look it up"]}
+```"#;
+    let backend = Arc::new(ScriptedBackend::new([
+        malformed.to_string(),
+        plan(r#"[{"id": "s1", "description": "Find the capital of France"}]"#),
+        "Paris".to_string(),
+    ]));
+    let agent = agent(&backend);
+
+    let answer = agent.run(TASK, RunMode::PlanAndExecute).await.unwrap();
+
+    assert_eq!(answer, "Paris");
+    let reask = &backend.requests()[1];
+    assert_eq!(message_text(&reask[1]), TASK);
+    assert_eq!(message_text(&reask[2]), malformed);
+    assert!(last_input(&backend, 1)
+        .starts_with("Your reply could not be used: the reply is missing `steps_to_take`."));
+    assert!(last_input(&backend, 1).contains("\"steps_to_take\""));
+    assert!(last_input(&backend, 2).starts_with("Find the capital of France"));
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn replan_after_an_unmet_goal() {
+    let backend = Arc::new(ScriptedBackend::new([
+        plan(TWO_STEPS),
+        "Rome".to_string(),
+        r#"{"continue_or_terminate": "CONTINUE", "key_points": ["Rome is the capital of Italy"]}"#
+            .to_string(),
+        plan(r#"[{"id": "s3", "description": "Look the capital up again", "depends_on": ["s1"]}]"#),
+        "Paris".to_string(),
+        r#"{"continue_or_terminate": "TERMINATE", "key_points": ["Paris"]}"#.to_string(),
+    ]));
+    let agent = agent(&backend).with_replanning(1);
+
+    let answer = agent.run(TASK, RunMode::PlanAndExecute).await.unwrap();
+
+    assert_eq!(answer, "Paris");
+    assert!(last_input(&backend, 3).contains("Result: Rome"));
+    let run = agent.current_run().unwrap();
+    assert_eq!(run.replans, 1);
+    let steps = run
+        .plan
+        .unwrap()
+        .steps
+        .into_iter()
+        .map(|step| step.id)
+        .collect::<Vec<String>>();
+    assert_eq!(steps, vec!["s1", "s3"]);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn a_failed_tool_call_goes_back_to_the_router() {
+    let backend = Arc::new(ScriptedBackend::new([
+        plan(r#"[{"id": "s1", "description": "Find the capital of France"}]"#),
+        r#"<tool_call>{"name": "no_such_tool", "arguments": {}}</tool_call>"#.to_string(),
+        "Paris".to_string(),
+    ]));
+    let agent = agent(&backend);
+
+    let answer = agent.run(TASK, RunMode::PlanAndExecute).await.unwrap();
+
+    assert_eq!(answer, "Paris");
+    let retry = last_input(&backend, 2);
+    assert!(retry.contains("Find the capital of France"));
+    assert!(retry.contains("no such tool is registered"));
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn budget_stops_the_run_with_what_it_has() {
+    let backend = Arc::new(ScriptedBackend::new([plan(TWO_STEPS), "Paris".to_string()]));
+    let agent = agent(&backend).with_budget(Budget {
+        max_tokens: None,
+        max_calls: Some(2),
+    });
+
+    let answer = agent.run(TASK, RunMode::PlanAndExecute).await.unwrap();
+
+    assert_eq!(
+        answer,
+        "Stopped early, call budget of 2 used up. Partial result:\n\n[s1] Paris"
+    );
+    assert_eq!(backend.requests().len(), 2);
+}
@@ -0,0 +1,75 @@
+//! Dispatching the tool calls of one model response.
+
+use async_trait::async_trait;
+use llama_agent::immutable_agent::*;
+use llama_agent::nous_structs::*;
+use llama_agent::tools::*;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+struct Echo;
+
+#[async_trait(?Send)]
+impl Tool for Echo {
+    fn name(&self) -> &str {
+        "echo"
+    }
+
+    fn description(&self) -> &str {
+        "Repeats its text."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": { "text": { "type": "string" } },
+            "required": ["text"]
+        })
+    }
+
+    async fn invoke(&self, _agent: &ImmutableAgent, args: &Value) -> anyhow::Result<String> {
+        Ok(args["text"].as_str().unwrap_or_default().to_string())
+    }
+}
+
+fn agent() -> ImmutableAgent {
+    ImmutableAgent::new("test", "").with_tools(ToolRegistry::new().with_tool(Arc::new(Echo)))
+}
+
+fn call(name: &str, arguments: Value) -> NousToolCall {
+    NousToolCall {
+        name: name.to_string(),
+        arguments,
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn a_failed_call_is_reported_next_to_the_others() {
+    let calls = [
+        call("echo", json!({ "text": "hello" })),
+        call("no_such_tool", json!({})),
+        call("echo", json!({})),
+    ];
+
+    let result = agent().run_tool_calls(&calls).await.unwrap();
+
+    assert_eq!(
+        result,
+        "Result of call 1 (echo):\nhello\n\n\
+         Result of call 2 (no_such_tool):\nError: tool 'no_such_tool' failed: no such tool is registered\n\n\
+         Result of call 3 (echo):\nError: tool 'echo' failed: invalid arguments: the arguments object is missing `text`. \
+         The arguments must follow this schema: {\"properties\":{\"text\":{\"type\":\"string\"}},\"required\":[\"text\"],\"type\":\"object\"}"
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn the_run_sees_an_error_only_when_every_call_failed() {
+    let calls = [call("no_such_tool", json!({})), call("echo", json!({}))];
+
+    let error = agent().run_tool_calls(&calls).await.unwrap_err();
+
+    assert_eq!(
+        error.to_string(),
+        "tool 'no_such_tool' failed: no such tool is registered"
+    );
+}